@@ -1,25 +1,38 @@
 use crate::{
+    chain::rewind_wallet,
     checkpoint::find_checkpoint,
-    constants::{HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, NETWORK},
-    Opt, Result, WalletError, DATA_PATH,
+    confirmed_height, Opt, Result, WalletError,
 };
-use rusqlite::{Connection, NO_PARAMS};
-use zcash_client_backend::encoding::decode_extended_full_viewing_key;
-use zcash_client_sqlite::{
-    wallet::init::{init_accounts_table, init_blocks_table},
-    WalletDB,
+use rusqlite::{params, Connection, NO_PARAMS};
+use serde::Serialize;
+use zcash_client_backend::{
+    data_api::WalletRead,
+    encoding::{decode_extended_full_viewing_key, encode_extended_full_viewing_key, encode_payment_address},
+    wallet::AccountId,
+};
+use zcash_client_sqlite::wallet::init::{init_accounts_table, init_blocks_table};
+use zcash_primitives::{
+    block::BlockHash,
+    consensus::{BlockHeight, Parameters},
+    keys::OutgoingViewingKey,
 };
-use zcash_primitives::{block::BlockHash, consensus::BlockHeight};
 use anyhow::Context;
 
-pub async fn init_account(lightnode_url: &str, viewing_key: String, height: u64) -> Result<()> {
-    let db_data = WalletDB::for_path(DATA_PATH, NETWORK)?;
-    let extfvks =
-        decode_extended_full_viewing_key(HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, &viewing_key)?
-            .ok_or(WalletError::Decode(viewing_key))?;
-    init_accounts_table(&db_data, &[extfvks]).context("init_accounts_table")?;
+/// Registers the viewing keys as accounts 0, 1, 2... in the order given
+pub async fn init_account(opts: &Opt, viewing_keys: Vec<String>, height: u64) -> Result<()> {
+    let db_data = opts.wallet_db()?;
+    let mut extfvks = Vec::new();
+    for viewing_key in viewing_keys {
+        let extfvk = decode_extended_full_viewing_key(
+            opts.network.hrp_sapling_extended_full_viewing_key(),
+            &viewing_key,
+        )?
+        .ok_or(WalletError::Decode(viewing_key))?;
+        extfvks.push(extfvk);
+    }
+    init_accounts_table(&db_data, &extfvks).context("init_accounts_table")?;
 
-    let checkpoint = find_checkpoint(lightnode_url, height).await?;
+    let checkpoint = find_checkpoint(opts, height).await?;
     init_blocks_table(
         &db_data,
         BlockHeight::from_u32(checkpoint.height as u32),
@@ -30,15 +43,143 @@ pub async fn init_account(lightnode_url: &str, viewing_key: String, height: u64)
     Ok(())
 }
 
-pub fn get_balance(opts: &Opt) -> Result<()> {
-    let data_connection = Connection::open(DATA_PATH)?;
-    let balance = data_connection.query_row(
-        "SELECT SUM(value) FROM received_notes WHERE spent IS NULL",
+/// Adds a viewing key to a wallet that already has accounts and returns the new account id.
+///
+/// The wallet is rewound to the birthday of the account, or to the first block of the wallet
+/// if there is none, so that the next scan of the block cache picks up its past notes.
+/// The rewind applies to every account: they are all scanned again from there.
+/// Blocks are not downloaded again.
+pub fn add_account(opts: &Opt, viewing_key: String, birth_height: Option<u64>) -> Result<AccountId> {
+    let extfvk = decode_extended_full_viewing_key(
+        opts.network.hrp_sapling_extended_full_viewing_key(),
+        &viewing_key,
+    )?
+    .ok_or_else(|| WalletError::Decode(viewing_key.clone()))?;
+    let (_, address) = extfvk.default_address().unwrap();
+
+    opts.check_network()?;
+    let data_connection = Connection::open(opts.data_path())?;
+    let first_height: Option<u32> =
+        data_connection.query_row("SELECT MIN(height) FROM blocks", NO_PARAMS, |row| row.get(0))?;
+    let first_height = first_height.ok_or(WalletError::AccountNotInitialized)?;
+    if let Some(birth_height) = birth_height {
+        if birth_height < u64::from(first_height) {
+            return Err(WalletError::BirthdayBeforeWallet(birth_height, first_height).into());
+        }
+    }
+
+    let account: u32 = data_connection.query_row(
+        "SELECT COALESCE(MAX(account) + 1, 0) FROM accounts",
         NO_PARAMS,
-        |row| row.get(0).or(Ok(0i64)),
+        |row| row.get(0),
+    )?;
+    data_connection.execute(
+        "INSERT INTO accounts (account, extfvk, address) VALUES (?1, ?2, ?3)",
+        params![
+            account,
+            encode_extended_full_viewing_key(
+                opts.network.hrp_sapling_extended_full_viewing_key(),
+                &extfvk
+            ),
+            encode_payment_address(opts.network.hrp_sapling_payment_address(), &address),
+        ],
     )?;
-    let balance = opts.unit.from_satoshis(balance as u64);
-    println!("Balance: {}", balance);
 
-    Ok(())
+    let rewind_height = match birth_height {
+        Some(birth_height) => (birth_height.saturating_sub(1) as u32).max(first_height),
+        None => first_height,
+    };
+    eprintln!(
+        "Rewinding the wallet to {}. Every account is scanned again from there",
+        rewind_height
+    );
+    rewind_wallet(opts, rewind_height)?;
+
+    Ok(AccountId(account))
+}
+
+/// Amounts of a balance in zatoshis
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Amounts {
+    /// Unspent notes, whatever their number of confirmations
+    pub total: u64,
+    /// Unspent notes at the anchor height, i.e. what prepare-tx can spend
+    pub spendable: u64,
+    /// Unspent notes that do not have enough confirmations yet
+    pub pending: u64,
+    /// Notes spent by transactions that do not have enough confirmations yet.
+    /// They come back if these transactions are reorganized out of the chain
+    /// and sync rewinds the wallet
+    pub locked: u64,
+}
+
+impl std::iter::Sum for Amounts {
+    fn sum<I: Iterator<Item = Amounts>>(iter: I) -> Self {
+        iter.fold(Amounts::default(), |sum, amounts| Amounts {
+            total: sum.total + amounts.total,
+            spendable: sum.spendable + amounts.spendable,
+            pending: sum.pending + amounts.pending,
+            locked: sum.locked + amounts.locked,
+        })
+    }
+}
+
+/// Balance of an account
+#[derive(Serialize)]
+pub struct Balance {
+    pub account: u32,
+    #[serde(flatten)]
+    pub amounts: Amounts,
+}
+
+/// Returns the balance of the selected account, or of every account.
+/// Notes are spendable from the same anchor height as in `prepare_tx`
+pub fn get_balance(opts: &Opt, all_accounts: bool) -> Result<Vec<Balance>> {
+    let wallet_db = opts.wallet_db()?;
+    let anchor_height = confirmed_height(&wallet_db)?;
+    let data_connection = Connection::open(opts.data_path())?;
+    let mut statement = data_connection.prepare(
+        "SELECT a.account, \
+         COALESCE(SUM(CASE WHEN n.spent IS NULL THEN n.value END), 0), \
+         COALESCE(SUM(CASE WHEN n.spent IS NULL AND t.block <= ?1 THEN n.value END), 0), \
+         COALESCE(SUM(CASE WHEN n.spent IS NOT NULL AND (s.block IS NULL OR s.block > ?1) \
+         THEN n.value END), 0) \
+         FROM accounts a \
+         LEFT JOIN received_notes n ON n.account = a.account \
+         LEFT JOIN transactions t ON t.id_tx = n.tx \
+         LEFT JOIN transactions s ON s.id_tx = n.spent \
+         WHERE ?2 OR a.account = ?3 \
+         GROUP BY a.account ORDER BY a.account",
+    )?;
+    let balances = statement.query_map(
+        params![anchor_height, all_accounts, opts.account.0],
+        |row| {
+            let total = row.get::<_, i64>(1)? as u64;
+            let spendable = row.get::<_, i64>(2)? as u64;
+            Ok(Balance {
+                account: row.get(0)?,
+                amounts: Amounts {
+                    total,
+                    spendable,
+                    pending: total - spendable,
+                    locked: row.get::<_, i64>(3)? as u64,
+                },
+            })
+        },
+    )?;
+    let balances: std::result::Result<Vec<_>, _> = balances.collect();
+    let balances = balances?;
+    if balances.is_empty() && !all_accounts {
+        return Err(WalletError::AccountNotFound(opts.account.0).into());
+    }
+    Ok(balances)
+}
+
+pub fn get_ovk(opts: &Opt) -> Result<OutgoingViewingKey> {
+    let wallet_db = opts.wallet_db()?;
+    let fvks = wallet_db.get_extended_full_viewing_keys()?;
+    let extfvk = fvks
+        .get(&opts.account)
+        .ok_or(WalletError::AccountNotFound(opts.account.0))?;
+    Ok(extfvk.fvk.ovk)
 }
@@ -1,53 +1,167 @@
 use crate::{
     connect_lightnode,
-    constants::NETWORK,
-    grpc::{BlockId, BlockRange, ChainSpec},
-    Result, WalletError, CACHE_PATH, DATA_PATH, MAX_REORG_DEPTH,
+    grpc::{
+        compact_tx_streamer_client::CompactTxStreamerClient, BlockId, BlockRange, ChainSpec,
+        CompactBlock,
+    },
+    confirmed_height, Opt, Result, WalletError, MAX_REORG_DEPTH,
 };
 use prost::{bytes::BytesMut, Message};
-use rusqlite::{params, Connection, NO_PARAMS};
-use zcash_client_backend::{
-    data_api::{chain::scan_cached_blocks, WalletRead}
-};
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use serde::Serialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::stream::{Stream, StreamExt};
+use tonic::transport::Channel;
+use zcash_client_backend::data_api::{chain::scan_cached_blocks, error::Error, WalletRead, WalletWrite};
 use zcash_client_sqlite::{
     chain::init::init_cache_database,
+    error::SqliteClientError,
     wallet::init::init_wallet_db,
-    BlockDB, WalletDB,
+    BlockDB,
 };
+use zcash_primitives::consensus::BlockHeight;
 
-pub fn init_db() -> Result<()> {
-    let db_data = WalletDB::for_path(DATA_PATH, NETWORK)?;
+pub fn init_db(opts: &Opt) -> Result<()> {
+    for path in [opts.data_path(), opts.cache_path()].iter() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    opts.record_network()?;
+    let db_data = opts.wallet_db()?;
     init_wallet_db(&db_data)?;
 
-    let db_cache = BlockDB::for_path(CACHE_PATH)?;
+    let db_cache = BlockDB::for_path(opts.cache_path())?;
     init_cache_database(&db_cache)?;
 
     Ok(())
 }
 
-pub async fn sync(lightnode_url: &str) -> Result<()> {
-    let lightnode_url = lightnode_url.to_string();
-    let cache_connection = Connection::open(CACHE_PATH)?;
-    let wallet_db = WalletDB::for_path(DATA_PATH, NETWORK)?;
-    let (_, last_bh) = wallet_db
-        .block_height_extrema()?
-        .ok_or(WalletError::AccountNotInitialized)?;
+/// Records the network in a wallet database created before init-db recorded it
+pub fn migrate_db(opts: &Opt) -> Result<()> {
+    let data_path = opts.data_path();
+    if !data_path.exists() {
+        return Err(WalletError::WalletNotFound(data_path.display().to_string()).into());
+    }
+    opts.record_network()
+}
 
-    let start_height: u64 = cache_connection
-        .query_row("SELECT MAX(height) FROM compactblocks", NO_PARAMS, |row| {
-            Ok(row.get::<_, u32>(0).map(u64::from).map(|h| h + 1).ok())
-        })?
-        .unwrap_or_else(|| u64::from(last_bh));
-    println!("Starting height: {}", start_height);
+#[derive(Serialize)]
+pub struct SyncResult {
+    pub start_height: u64,
+    pub synced_height: u64,
+    /// Blocks above this height may still be reorganized.
+    /// The notes they contain are unconfirmed
+    pub confirmed_height: u64,
+}
 
-    let mut client = connect_lightnode(lightnode_url).await?;
-    let latest_block = client
-        .get_latest_block(tonic::Request::new(ChainSpec {}))
-        .await?
-        .into_inner();
+/// Number of times sync starts over after the chain changed under it
+const MAX_SYNC_ATTEMPTS: usize = 3;
+/// Blocks downloaded, written in one transaction, then scanned, at a time
+const SYNC_BATCH_SIZE: u64 = 1_000;
+
+/// Downloads and scans the blocks up to the tip, in batches.
+///
+/// Ctrl-C stops the sync wherever it waits for the server: while connecting, comparing
+/// the chains or downloading a batch. The batch being downloaded is rolled back, and the next
+/// sync resumes after the last batch that was written. A batch that is being scanned
+/// is finished first
+pub async fn sync(opts: &Opt) -> Result<SyncResult> {
+    tokio::select! {
+        result = sync_chain(opts) => result,
+        signal = tokio::signal::ctrl_c() => {
+            signal?;
+            eprintln!();
+            let (cached_height, scanned_height) = saved_heights(opts)?;
+            Err(WalletError::Interrupted(cached_height.unwrap_or(scanned_height)).into())
+        }
+    }
+}
+
+async fn sync_chain(opts: &Opt) -> Result<SyncResult> {
+    let mut client = connect_lightnode(opts).await?;
+    for _ in 0..MAX_SYNC_ATTEMPTS {
+        let latest_block = client
+            .get_latest_block(tonic::Request::new(ChainSpec {}))
+            .await?
+            .into_inner();
 
-    let synced_height = latest_block.height - MAX_REORG_DEPTH;
-    let mut blocks = client
+        if let Some(fork_height) = find_fork_height(opts, &mut client, latest_block.height).await? {
+            eprintln!("Chain reorganization detected. Rewinding to {}", fork_height);
+            rewind(opts, fork_height)?;
+        }
+
+        let start_height = download_start_height(opts)?;
+        eprintln!("Starting height: {}", start_height);
+
+        // Go all the way to the tip. The last MAX_REORG_DEPTH blocks can still change:
+        // the next sync checks them against the server and rewinds if needed
+        let synced_height = latest_block.height;
+        let result = sync_range(opts, &mut client, start_height, synced_height).await;
+        match result {
+            Ok(()) => {
+                return Ok(SyncResult {
+                    start_height,
+                    synced_height,
+                    confirmed_height: u64::from(confirmed_height(&opts.wallet_db()?)?),
+                })
+            }
+            Err(error) if is_invalid_chain(&error) => {
+                // The chain changed while we were downloading: the cache does not follow
+                // the scanned blocks anymore. Drop the blocks that were not scanned and start over
+                eprintln!("The chain changed during the sync. Retrying");
+                let wallet_db = opts.wallet_db()?;
+                if let Some((_, scanned_height)) = wallet_db.block_height_extrema()? {
+                    truncate_cache(opts, u64::from(scanned_height))?;
+                }
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    Err(WalletError::ChainUnstable(MAX_SYNC_ATTEMPTS).into())
+}
+
+async fn sync_range(
+    opts: &Opt,
+    client: &mut CompactTxStreamerClient<Channel>,
+    start_height: u64,
+    end_height: u64,
+) -> Result<()> {
+    // Blocks left in the cache by a previous sync that stopped before scanning them
+    scan(opts)?;
+
+    let progress = Progress::new(start_height, end_height);
+    let mut height = start_height;
+    while height <= end_height {
+        let batch_end = (height + SYNC_BATCH_SIZE - 1).min(end_height);
+        download_blocks(opts, client, height, batch_end).await?;
+        scan(opts)?;
+        progress.report(batch_end);
+        height = batch_end + 1;
+    }
+    if start_height <= end_height {
+        eprintln!();
+    }
+    Ok(())
+}
+
+pub fn scan(opts: &Opt) -> Result<()> {
+    let cache = BlockDB::for_path(opts.cache_path())?;
+    let db_read = opts.wallet_db()?;
+    let mut data = db_read.get_update_ops()?;
+    scan_cached_blocks(&opts.network, &cache, &mut data, None)?;
+    Ok(())
+}
+
+/// Downloads the blocks from `start_height` to `end_height` into the cache, in a single transaction
+async fn download_blocks(
+    opts: &Opt,
+    client: &mut CompactTxStreamerClient<Channel>,
+    start_height: u64,
+    end_height: u64,
+) -> Result<()> {
+    let blocks = client
         .get_block_range(tonic::Request::new(BlockRange {
             start: Some(BlockId {
                 hash: Vec::new(),
@@ -55,33 +169,444 @@ pub async fn sync(lightnode_url: &str) -> Result<()> {
             }),
             end: Some(BlockId {
                 hash: Vec::new(),
-                height: synced_height,
+                height: end_height,
             }),
         }))
         .await?
         .into_inner();
+    cache_blocks(&opts.cache_path(), blocks).await
+}
 
-    let mut statement =
-        cache_connection.prepare("INSERT INTO compactblocks (height, data) VALUES (?, ?)")?;
-    while let Some(cb) = blocks.message().await? {
-        let mut cb_bytes = BytesMut::with_capacity(cb.encoded_len());
-        cb.encode_raw(&mut cb_bytes);
-        statement.execute(params![cb.height as u32, cb_bytes.to_vec()])?;
+/// Writes the blocks of the stream into the cache, in a single transaction.
+/// Nothing is written if the stream fails, or if the future is dropped before the end
+async fn cache_blocks<S>(cache_path: &Path, blocks: S) -> Result<()>
+where
+    S: Stream<Item = std::result::Result<CompactBlock, tonic::Status>>,
+{
+    tokio::pin!(blocks);
+    let mut cache_connection = Connection::open(cache_path)?;
+    let transaction = cache_connection.transaction()?;
+    {
+        let mut statement =
+            transaction.prepare("INSERT INTO compactblocks (height, data) VALUES (?, ?)")?;
+        // Returning early drops the transaction, which rolls it back
+        while let Some(cb) = blocks.next().await {
+            let cb = cb?;
+            let mut cb_bytes = BytesMut::with_capacity(cb.encoded_len());
+            cb.encode_raw(&mut cb_bytes);
+            statement.execute(params![cb.height as u32, cb_bytes.to_vec()])?;
+        }
     }
+    transaction.commit()?;
+    Ok(())
+}
 
-    println!("Synced to {}", synced_height);
+struct Progress {
+    start_height: u64,
+    end_height: u64,
+    started: Instant,
+}
 
-    scan()?;
+impl Progress {
+    fn new(start_height: u64, end_height: u64) -> Self {
+        Progress {
+            start_height,
+            end_height,
+            started: Instant::now(),
+        }
+    }
 
-    Ok(())
+    /// Shows the percentage of blocks done and the estimated time left on stderr
+    fn report(&self, height: u64) {
+        eprint!("\r{} ", self.describe(height, self.started.elapsed()));
+    }
+
+    fn describe(&self, height: u64, elapsed: Duration) -> String {
+        let done = height + 1 - self.start_height;
+        let total = self.end_height + 1 - self.start_height;
+        let percent = done as f64 * 100.0 / total as f64;
+        let eta = elapsed.mul_f64((total - done) as f64 / done as f64).as_secs();
+        format!(
+            "Synced {}/{} ({:.1}%) ETA {}m{:02}s",
+            height,
+            self.end_height,
+            percent,
+            eta / 60,
+            eta % 60
+        )
+    }
 }
 
-pub fn scan() -> Result<()> {
-    let cache = BlockDB::for_path(CACHE_PATH)?;
-    let db_read = WalletDB::for_path(DATA_PATH, NETWORK)?;
-    let mut data = db_read.get_update_ops()?;
-    scan_cached_blocks(&NETWORK, &cache, &mut data, None)?;
+/// Downloads continue after the last cached block, or from the last scanned block
+/// if the cache is empty
+fn download_start_height(opts: &Opt) -> Result<u64> {
+    let (cached_height, scanned_height) = saved_heights(opts)?;
+    Ok(resume_height(cached_height, scanned_height))
+}
 
-    println!("Scan completed");
+fn resume_height(cached_height: Option<u64>, scanned_height: u64) -> u64 {
+    cached_height.map_or(scanned_height, |h| h + 1)
+}
+
+/// Height of the last block in the cache, if any, and of the last scanned block
+fn saved_heights(opts: &Opt) -> Result<(Option<u64>, u64)> {
+    let wallet_db = opts.wallet_db()?;
+    let (_, last_bh) = wallet_db
+        .block_height_extrema()?
+        .ok_or(WalletError::AccountNotInitialized)?;
+    let cache_connection = Connection::open(opts.cache_path())?;
+    let cached_height: Option<u32> = cache_connection.query_row(
+        "SELECT MAX(height) FROM compactblocks",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    Ok((cached_height.map(u64::from), u64::from(last_bh)))
+}
+
+/// Compares our most recent blocks with the server's, going down at most `MAX_REORG_DEPTH` blocks.
+/// Returns the height of the last common block if our chain has diverged,
+/// `None` if it is still on the server's chain
+async fn find_fork_height(
+    opts: &Opt,
+    client: &mut CompactTxStreamerClient<Channel>,
+    latest_height: u64,
+) -> Result<Option<u64>> {
+    let wallet_db = opts.wallet_db()?;
+    let (first_height, scanned_height) = wallet_db
+        .block_height_extrema()?
+        .ok_or(WalletError::AccountNotInitialized)?;
+    let (first_height, scanned_height) = (u64::from(first_height), u64::from(scanned_height));
+    let cache_connection = Connection::open(opts.cache_path())?;
+    let cached_height: Option<u32> = cache_connection.query_row(
+        "SELECT MAX(height) FROM compactblocks",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let top_height = cached_height.map_or(scanned_height, |h| u64::from(h).max(scanned_height));
+
+    let search = ForkSearch::new(top_height, first_height, latest_height);
+    let mut height = search.start_height;
+    loop {
+        let server_hash = client
+            .get_block(BlockId {
+                height,
+                hash: Vec::new(),
+            })
+            .await?
+            .into_inner()
+            .hash;
+        let local_hash = local_block_hash(opts, &cache_connection, height)?;
+        match search.compare(height, local_hash.as_deref(), &server_hash)? {
+            ForkSearchStep::Next(next_height) => height = next_height,
+            ForkSearchStep::Done(fork_height) => return Ok(fork_height),
+        }
+    }
+}
+
+/// Search of the last block that our chain has in common with the server's, from the top down
+struct ForkSearch {
+    top_height: u64,
+    lowest_height: u64,
+    start_height: u64,
+}
+
+enum ForkSearchStep {
+    /// Compare the blocks at this height next
+    Next(u64),
+    /// The height of the last common block if our chain has diverged
+    Done(Option<u64>),
+}
+
+impl ForkSearch {
+    /// `top_height` is our last block, `first_height` the birthday checkpoint of the wallet
+    /// and `latest_height` the tip of the server
+    fn new(top_height: u64, first_height: u64, latest_height: u64) -> Self {
+        ForkSearch {
+            top_height,
+            // Blocks before the birthday checkpoint of the wallet cannot be recovered
+            lowest_height: top_height.saturating_sub(MAX_REORG_DEPTH).max(first_height),
+            // A server that is behind, like a lagging backend of a load balancer,
+            // can only be checked up to its tip
+            start_height: top_height.min(latest_height),
+        }
+    }
+
+    fn compare(&self, height: u64, local_hash: Option<&[u8]>, server_hash: &[u8]) -> Result<ForkSearchStep> {
+        if local_hash == Some(server_hash) {
+            // Matching at the first height means that nothing forked
+            let fork_height = Some(height).filter(|&height| height != self.start_height);
+            return Ok(ForkSearchStep::Done(fork_height));
+        }
+        if height <= self.lowest_height {
+            return Err(WalletError::ReorgTooDeep(self.top_height, MAX_REORG_DEPTH).into());
+        }
+        Ok(ForkSearchStep::Next(height - 1))
+    }
+}
+
+/// Hash of a block from the cache, or from the wallet if the cache does not have it
+fn local_block_hash(
+    opts: &Opt,
+    cache_connection: &Connection,
+    height: u64,
+) -> Result<Option<Vec<u8>>> {
+    let data: Option<Vec<u8>> = cache_connection
+        .query_row(
+            "SELECT data FROM compactblocks WHERE height = ?1",
+            params![height as u32],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(data) = data {
+        let block = CompactBlock::decode(&data[..])?;
+        return Ok(Some(block.hash));
+    }
+    let data_connection = Connection::open(opts.data_path())?;
+    let hash = data_connection
+        .query_row(
+            "SELECT hash FROM blocks WHERE height = ?1",
+            params![height as u32],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(hash)
+}
+
+/// Forgets the blocks after `height`, in the wallet and in the cache, so that they get
+/// downloaded and scanned again
+fn rewind(opts: &Opt, height: u64) -> Result<()> {
+    rewind_wallet(opts, height as u32)?;
+    truncate_cache(opts, height)
+}
+
+/// Forgets the blocks after `height` in the wallet, so that they get scanned again.
+///
+/// `rewind_to_height` un-mines the transactions of these blocks but keeps their effect
+/// on the notes. Undo it: the notes they spent become unspent again and the notes they
+/// received are dropped. Scanning restores both if the transactions are mined again
+pub(crate) fn rewind_wallet(opts: &Opt, height: u32) -> Result<()> {
+    let wallet_db = opts.wallet_db()?;
+    let mut db_update = wallet_db.get_update_ops()?;
+    db_update.rewind_to_height(BlockHeight::from_u32(height))?;
+    let mut data_connection = Connection::open(opts.data_path())?;
+    forget_unmined_notes(&mut data_connection)
+}
+
+fn forget_unmined_notes(data_connection: &mut Connection) -> Result<()> {
+    let transaction = data_connection.transaction()?;
+    transaction.execute(
+        "UPDATE received_notes SET spent = NULL \
+         WHERE spent IN (SELECT id_tx FROM transactions WHERE block IS NULL)",
+        NO_PARAMS,
+    )?;
+    transaction.execute(
+        "DELETE FROM received_notes \
+         WHERE tx IN (SELECT id_tx FROM transactions WHERE block IS NULL)",
+        NO_PARAMS,
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn truncate_cache(opts: &Opt, height: u64) -> Result<()> {
+    let cache_connection = Connection::open(opts.cache_path())?;
+    cache_connection.execute(
+        "DELETE FROM compactblocks WHERE height > ?1",
+        params![height as u32],
+    )?;
     Ok(())
 }
+
+fn is_invalid_chain(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<SqliteClientError>(),
+        Some(SqliteClientError::BackendError(Error::InvalidChain(..)))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    struct TestCache(PathBuf);
+
+    impl TestCache {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "zcash-coldwallet-{}-{}.sqlite3",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let connection = Connection::open(&path).unwrap();
+            connection
+                .execute(
+                    "CREATE TABLE compactblocks (height INTEGER PRIMARY KEY, data BLOB NOT NULL)",
+                    NO_PARAMS,
+                )
+                .unwrap();
+            TestCache(path)
+        }
+
+        fn heights(&self) -> Vec<u32> {
+            let connection = Connection::open(&self.0).unwrap();
+            let mut statement = connection
+                .prepare("SELECT height FROM compactblocks ORDER BY height")
+                .unwrap();
+            let heights = statement.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+            heights.map(|h| h.unwrap()).collect()
+        }
+    }
+
+    impl Drop for TestCache {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn block(height: u64) -> CompactBlock {
+        CompactBlock {
+            height,
+            hash: vec![height as u8; 32],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn complete_download_is_committed() {
+        let cache = TestCache::new("committed");
+        let blocks = tokio::stream::iter(vec![Ok(block(10)), Ok(block(11)), Ok(block(12))]);
+        cache_blocks(&cache.0, blocks).await.unwrap();
+        assert_eq!(cache.heights(), vec![10, 11, 12]);
+    }
+
+    #[tokio::test]
+    async fn failed_download_is_rolled_back() {
+        let cache = TestCache::new("rolled-back");
+        cache_blocks(&cache.0, tokio::stream::iter(vec![Ok(block(10))]))
+            .await
+            .unwrap();
+        let blocks = tokio::stream::iter(vec![
+            Ok(block(11)),
+            Ok(block(12)),
+            Err(tonic::Status::unavailable("connection lost")),
+        ]);
+        assert!(cache_blocks(&cache.0, blocks).await.is_err());
+        // The previous batch is kept and the sync resumes right after it
+        assert_eq!(cache.heights(), vec![10]);
+    }
+
+    #[test]
+    fn unmined_transactions_release_their_notes() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE transactions (id_tx INTEGER PRIMARY KEY, block INTEGER);
+                 CREATE TABLE received_notes (id_note INTEGER PRIMARY KEY, tx INTEGER NOT NULL,
+                 value INTEGER NOT NULL, spent INTEGER);
+                 -- Transactions 1 and 2 are still mined, 3 and 4 were reorganized out
+                 INSERT INTO transactions VALUES (1, 100), (2, 105), (3, NULL), (4, NULL);
+                 INSERT INTO received_notes VALUES
+                 (1, 1, 10, 2),
+                 (2, 1, 20, 3),
+                 (3, 2, 30, NULL),
+                 (4, 3, 40, NULL),
+                 (5, 4, 50, NULL);",
+            )
+            .unwrap();
+        forget_unmined_notes(&mut connection).unwrap();
+        let mut statement = connection
+            .prepare("SELECT id_note, spent FROM received_notes ORDER BY id_note")
+            .unwrap();
+        let notes: Vec<(i64, Option<i64>)> = statement
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|note| note.unwrap())
+            .collect();
+        assert_eq!(notes, vec![(1, Some(2)), (2, None), (3, None)]);
+    }
+
+    /// Hash of the block at `height` of a chain that has diverged from ours above `fork_height`
+    fn hash(height: u64, fork_height: Option<u64>) -> Vec<u8> {
+        let forked = fork_height.map_or(false, |fork_height| height > fork_height);
+        vec![height as u8, forked as u8]
+    }
+
+    /// Runs the search of `find_fork_height` against in-memory chains
+    fn find_fork(
+        top_height: u64,
+        first_height: u64,
+        latest_height: u64,
+        server_fork_height: Option<u64>,
+    ) -> Result<Option<u64>> {
+        let search = ForkSearch::new(top_height, first_height, latest_height);
+        let mut height = search.start_height;
+        loop {
+            let local_hash = Some(hash(height, None)).filter(|_| height <= top_height);
+            let server_hash = hash(height, server_fork_height);
+            match search.compare(height, local_hash.as_deref(), &server_hash)? {
+                ForkSearchStep::Next(next_height) => height = next_height,
+                ForkSearchStep::Done(fork_height) => return Ok(fork_height),
+            }
+        }
+    }
+
+    #[test]
+    fn no_fork() {
+        assert_eq!(find_fork(1_000, 900, 1_000, None).unwrap(), None);
+        assert_eq!(find_fork(1_000, 900, 1_010, None).unwrap(), None);
+        // A server that lags behind is not a reorganization
+        assert_eq!(find_fork(1_000, 900, 998, None).unwrap(), None);
+        // Blocks of the server above our top do not matter
+        assert_eq!(find_fork(1_000, 900, 1_010, Some(1_000)).unwrap(), None);
+    }
+
+    #[test]
+    fn fork_below_the_top() {
+        for depth in 1..MAX_REORG_DEPTH {
+            let fork_height = 1_000 - depth;
+            assert_eq!(
+                find_fork(1_000, 900, 1_010, Some(fork_height)).unwrap(),
+                Some(fork_height)
+            );
+        }
+        assert_eq!(find_fork(1_000, 900, 1_000, Some(995)).unwrap(), Some(995));
+        // Behind and forked
+        assert_eq!(find_fork(1_000, 900, 998, Some(995)).unwrap(), Some(995));
+    }
+
+    #[test]
+    fn fork_deeper_than_max_reorg_depth() {
+        let error = find_fork(1_000, 900, 1_010, Some(1_000 - MAX_REORG_DEPTH - 1)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<WalletError>(),
+            Some(WalletError::ReorgTooDeep(1_000, _))
+        ));
+        // Nor below the birthday of the wallet
+        assert!(find_fork(1_000, 998, 1_010, Some(996)).is_err());
+    }
+
+    #[test]
+    fn resume_after_the_cache_or_the_scanned_blocks() {
+        assert_eq!(resume_height(Some(1_000), 900), 1_001);
+        assert_eq!(resume_height(None, 900), 900);
+    }
+
+    #[test]
+    fn progress() {
+        let progress = Progress::new(100, 199);
+        assert_eq!(
+            progress.describe(149, Duration::from_secs(10)),
+            "Synced 149/199 (50.0%) ETA 0m10s"
+        );
+        assert_eq!(
+            progress.describe(124, Duration::from_secs(100)),
+            "Synced 124/199 (25.0%) ETA 5m00s"
+        );
+        assert_eq!(
+            progress.describe(199, Duration::from_secs(60)),
+            "Synced 199/199 (100.0%) ETA 0m00s"
+        );
+    }
+}
@@ -1,8 +1,7 @@
-use crate::constants::NETWORK;
 use crate::{
     connect_lightnode,
     grpc::{BlockId, ChainSpec},
-    Result,
+    Opt, Result,
 };
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
 use zcash_primitives::consensus::{NetworkUpgrade, Parameters};
@@ -15,9 +14,8 @@ pub struct Checkpoint {
     pub sapling_tree: String,
 }
 
-pub async fn find_checkpoint(lightnode_url: &str, height: u64) -> Result<Checkpoint> {
-    let lightnode_url = lightnode_url.to_string();
-    let mut client = connect_lightnode(lightnode_url).await?;
+pub async fn find_checkpoint(opts: &Opt, height: u64) -> Result<Checkpoint> {
+    let mut client = connect_lightnode(opts).await?;
     let tree_state = client
         .get_tree_state(BlockId {
             height,
@@ -37,9 +35,10 @@ pub async fn find_checkpoint(lightnode_url: &str, height: u64) -> Result<Checkpo
     Ok(checkpoint)
 }
 
-pub async fn find_height(lightnode_url: &str, date: &NaiveDate) -> Result<u64> {
-    let mut client = connect_lightnode(lightnode_url.to_string()).await?;
-    let mut low: u64 = NETWORK
+pub async fn find_height(opts: &Opt, date: &NaiveDate) -> Result<u64> {
+    let mut client = connect_lightnode(opts).await?;
+    let mut low: u64 = opts
+        .network
         .activation_height(NetworkUpgrade::Sapling)
         .unwrap()
         .into();
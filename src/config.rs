@@ -0,0 +1,117 @@
+use crate::network::{NetworkType, Params, RegtestParams};
+use crate::review::check_supported_fee;
+use crate::{Result, ZECUnit};
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+pub const DATA_DIR_ENV: &str = "ZCASH_COLDWALLET_DATA_DIR";
+const CONFIG_FILENAME: &str = "config.toml";
+
+/// Settings read from the configuration files:
+/// - the user configuration file, `<config dir>/zcash-coldwallet/config.toml`,
+/// for example `~/.config/zcash-coldwallet/config.toml` on Linux
+/// - `config.toml` in the data directory, which overrides the user configuration
+///
+/// Command line options override both
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub data_dir: Option<PathBuf>,
+    pub lightwalletd_url: Option<String>,
+    pub network: Option<String>,
+    pub unit: Option<String>,
+    /// Wallet database, relative to the data directory
+    pub data_path: Option<PathBuf>,
+    /// Block cache database, relative to the data directory
+    pub cache_path: Option<PathBuf>,
+    /// Transaction fee in zatoshis. Only the standard fee is supported
+    pub fee: Option<u64>,
+    pub tls: Option<TlsConfig>,
+    pub regtest: Option<RegtestParams>,
+}
+
+/// TLS settings of the connection to lightwalletd.
+/// TLS is used when the server URL starts with https
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// PEM file of the certificate authority of a server with a private certificate
+    pub ca_certificate: Option<PathBuf>,
+    /// Name to check in the server certificate when it is not the host of the URL
+    pub domain_name: Option<String>,
+}
+
+impl Config {
+    pub fn user_config_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("zcash-coldwallet").join(CONFIG_FILENAME))
+    }
+
+    /// Loads the user configuration file. A missing file gives the default configuration
+    pub fn load() -> Result<Config> {
+        match Config::user_config_path() {
+            Some(path) => Config::load_from(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    fn load_from(path: &Path) -> Result<Config> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let invalid = || format!("Invalid configuration file {}", path.display());
+        let config = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read the configuration file {}", path.display()))?;
+        let config: Config = toml::from_str(&config).with_context(invalid)?;
+        config.check().with_context(invalid)?;
+        Ok(config)
+    }
+
+    /// Parses the values that are not checked by deserialization
+    fn check(&self) -> Result<()> {
+        if let Some(network) = self.network.as_ref() {
+            network.parse::<NetworkType>()?;
+        }
+        if let Some(unit) = self.unit.as_ref() {
+            unit.parse::<ZECUnit>()?;
+        }
+        if let Some(fee) = self.fee {
+            check_supported_fee(fee)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the configuration file of the data directory on top of this one
+    pub fn with_data_dir(self, data_dir: &Path) -> Result<Config> {
+        let local = Config::load_from(&data_dir.join(CONFIG_FILENAME))?;
+        Ok(Config {
+            data_dir: self.data_dir,
+            lightwalletd_url: local.lightwalletd_url.or(self.lightwalletd_url),
+            network: local.network.or(self.network),
+            unit: local.unit.or(self.unit),
+            data_path: local.data_path.or(self.data_path),
+            cache_path: local.cache_path.or(self.cache_path),
+            fee: local.fee.or(self.fee),
+            tls: local.tls.or(self.tls),
+            regtest: local.regtest.or(self.regtest),
+        })
+    }
+
+    /// Picks the data directory: command line first, then the environment variable,
+    /// then the configuration file. Defaults to the current directory
+    pub fn data_dir(&self, data_dir: Option<PathBuf>) -> PathBuf {
+        data_dir
+            .or_else(|| std::env::var_os(DATA_DIR_ENV).map(PathBuf::from))
+            .or_else(|| self.data_dir.clone())
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    /// Builds the parameters of the network. Regtest takes its activation heights
+    /// and address encodings from the `[regtest]` section when there is one
+    pub fn network_params(&self, network_type: NetworkType) -> Params {
+        match (network_type, self.regtest.as_ref()) {
+            (NetworkType::Regtest, Some(regtest)) => Params::regtest(regtest.clone()),
+            _ => Params::new(network_type),
+        }
+    }
+}
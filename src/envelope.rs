@@ -0,0 +1,231 @@
+use crate::{network::NetworkType, Result, Tx, WalletError};
+use blake2b_simd::{Hash, Params};
+use serde::{Deserialize, Serialize};
+use zcash_primitives::keys::OutgoingViewingKey;
+
+/// Version of the unsigned transaction file format.
+/// Bump it whenever `Tx` changes in an incompatible way
+pub const TX_ENVELOPE_VERSION: u32 = 1;
+
+const CHECKSUM_PERSONALIZATION: &[u8; 16] = b"ZColdWalletTxSum";
+const MAC_PERSONALIZATION: &[u8; 16] = b"ZColdWalletTxMac";
+
+/// Wraps the unsigned transaction that travels from the online computer
+/// to the offline computer.
+///
+/// The checksum catches truncated or corrupted files. The MAC is keyed
+/// with the outgoing viewing key of the account, so the signer can tell
+/// that the file was made by a wallet that holds its viewing key.
+#[derive(Serialize, Deserialize)]
+pub struct TxEnvelope {
+    version: u32,
+    network: String,
+    payload: String,
+    checksum: String,
+    mac: Option<String>,
+}
+
+fn digest(params: &Params, version: u32, network: &str, payload: &[u8]) -> Hash {
+    let mut state = params.to_state();
+    state.update(&version.to_le_bytes());
+    state.update(&(network.len() as u32).to_le_bytes());
+    state.update(network.as_bytes());
+    state.update(payload);
+    state.finalize()
+}
+
+/// Also used by the PSZT format
+pub(crate) fn checksum(version: u32, network: &str, payload: &[u8]) -> Hash {
+    digest(
+        Params::new()
+            .hash_length(32)
+            .personal(CHECKSUM_PERSONALIZATION),
+        version,
+        network,
+        payload,
+    )
+}
+
+pub(crate) fn mac(ovk: &OutgoingViewingKey, version: u32, network: &str, payload: &[u8]) -> Hash {
+    digest(
+        Params::new()
+            .hash_length(32)
+            .key(&ovk.0)
+            .personal(MAC_PERSONALIZATION),
+        version,
+        network,
+        payload,
+    )
+}
+
+/// Serializes the transaction into an authenticated envelope
+pub fn seal(tx: &Tx, ovk: &OutgoingViewingKey, network: NetworkType) -> Result<String> {
+    let network = network.to_string();
+    let payload = serde_json::to_string(tx)?;
+    let envelope = TxEnvelope {
+        version: TX_ENVELOPE_VERSION,
+        checksum: checksum(TX_ENVELOPE_VERSION, &network, payload.as_bytes())
+            .to_hex()
+            .to_string(),
+        mac: Some(
+            mac(ovk, TX_ENVELOPE_VERSION, &network, payload.as_bytes())
+                .to_hex()
+                .to_string(),
+        ),
+        network,
+        payload,
+    };
+    let envelope = serde_json::to_string(&envelope)?;
+    Ok(envelope)
+}
+
+/// Checks the envelope and extracts the transaction.
+///
+/// The MAC is verified when the caller has the outgoing viewing key
+pub fn open(envelope: &str, ovk: Option<&OutgoingViewingKey>, network: NetworkType) -> Result<Tx> {
+    let envelope: TxEnvelope =
+        serde_json::from_str(envelope).or(Err(WalletError::TxParse))?;
+    if envelope.version != TX_ENVELOPE_VERSION {
+        return Err(WalletError::TxVersion(envelope.version, TX_ENVELOPE_VERSION).into());
+    }
+    if envelope.network != network.to_string() {
+        return Err(WalletError::WrongNetwork(envelope.network, network.to_string()).into());
+    }
+    let expected = checksum(envelope.version, &envelope.network, envelope.payload.as_bytes());
+    if hex::decode(&envelope.checksum).ok().as_deref() != Some(expected.as_bytes()) {
+        return Err(WalletError::Checksum.into());
+    }
+    if let Some(ovk) = ovk {
+        let expected = mac(ovk, envelope.version, &envelope.network, envelope.payload.as_bytes());
+        let mac = envelope
+            .mac
+            .as_ref()
+            .and_then(|mac| hex::decode(mac).ok())
+            .ok_or(WalletError::Mac)?;
+        if expected != mac[..] {
+            return Err(WalletError::Mac.into());
+        }
+    }
+    let tx: Tx = serde_json::from_str(&envelope.payload).or(Err(WalletError::TxParse))?;
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddressType, TxOut};
+
+    fn test_tx() -> Tx {
+        Tx {
+            height: 1_200_000,
+            inputs: Vec::new(),
+            outputs: vec![TxOut {
+                addr_type: AddressType::Shielded,
+                addr: "ztestsapling1recipient".to_string(),
+                amount: 50_000,
+                ovk: hex::encode([1u8; 32]),
+                memo: None,
+            }],
+            change: None,
+            fee: 1_000,
+        }
+    }
+
+    fn error(result: Result<Tx>) -> WalletError {
+        result
+            .expect_err("the envelope should be rejected")
+            .downcast::<WalletError>()
+            .unwrap()
+    }
+
+    fn tamper(envelope: &str, f: impl FnOnce(&mut TxEnvelope)) -> String {
+        let mut envelope: TxEnvelope = serde_json::from_str(envelope).unwrap();
+        f(&mut envelope);
+        serde_json::to_string(&envelope).unwrap()
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let ovk = OutgoingViewingKey([7u8; 32]);
+        let tx = test_tx();
+        let envelope = seal(&tx, &ovk, NetworkType::Test).unwrap();
+        let opened = open(&envelope, Some(&ovk), NetworkType::Test).unwrap();
+        assert_eq!(
+            serde_json::to_string(&opened).unwrap(),
+            serde_json::to_string(&tx).unwrap()
+        );
+        // Reviewing on the online computer does not need the key
+        assert!(open(&envelope, None, NetworkType::Test).is_ok());
+    }
+
+    #[test]
+    fn open_rejects_wrong_network() {
+        let ovk = OutgoingViewingKey([7u8; 32]);
+        let envelope = seal(&test_tx(), &ovk, NetworkType::Test).unwrap();
+        assert!(matches!(
+            error(open(&envelope, Some(&ovk), NetworkType::Main)),
+            WalletError::WrongNetwork(..)
+        ));
+    }
+
+    #[test]
+    fn open_rejects_corrupted_payload() {
+        let ovk = OutgoingViewingKey([7u8; 32]);
+        let envelope = seal(&test_tx(), &ovk, NetworkType::Test).unwrap();
+        let corrupted = tamper(&envelope, |e| e.payload = e.payload.replace("50000", "50001"));
+        assert!(matches!(
+            error(open(&corrupted, None, NetworkType::Test)),
+            WalletError::Checksum
+        ));
+        let truncated = tamper(&envelope, |e| e.checksum.truncate(10));
+        assert!(matches!(
+            error(open(&truncated, None, NetworkType::Test)),
+            WalletError::Checksum
+        ));
+    }
+
+    #[test]
+    fn open_rejects_forged_payload() {
+        // Someone without the viewing key can fix the checksum but not the MAC
+        let ovk = OutgoingViewingKey([7u8; 32]);
+        let envelope = seal(&test_tx(), &ovk, NetworkType::Test).unwrap();
+        let forged = tamper(&envelope, |e| {
+            e.payload = e.payload.replace("50000", "50001");
+            e.checksum = checksum(e.version, &e.network, e.payload.as_bytes())
+                .to_hex()
+                .to_string();
+        });
+        assert!(open(&forged, None, NetworkType::Test).is_ok());
+        assert!(matches!(
+            error(open(&forged, Some(&ovk), NetworkType::Test)),
+            WalletError::Mac
+        ));
+    }
+
+    #[test]
+    fn open_rejects_wrong_or_missing_mac() {
+        let ovk = OutgoingViewingKey([7u8; 32]);
+        let envelope = seal(&test_tx(), &ovk, NetworkType::Test).unwrap();
+        let other_ovk = OutgoingViewingKey([8u8; 32]);
+        assert!(matches!(
+            error(open(&envelope, Some(&other_ovk), NetworkType::Test)),
+            WalletError::Mac
+        ));
+        let stripped = tamper(&envelope, |e| e.mac = None);
+        assert!(matches!(
+            error(open(&stripped, Some(&ovk), NetworkType::Test)),
+            WalletError::Mac
+        ));
+    }
+
+    #[test]
+    fn open_rejects_other_versions() {
+        let ovk = OutgoingViewingKey([7u8; 32]);
+        let envelope = seal(&test_tx(), &ovk, NetworkType::Test).unwrap();
+        let newer = tamper(&envelope, |e| e.version = TX_ENVELOPE_VERSION + 1);
+        assert!(matches!(
+            error(open(&newer, Some(&ovk), NetworkType::Test)),
+            WalletError::TxVersion(..)
+        ));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::{confirmed_height, Opt, Result, WalletError};
+use chrono::{NaiveDate, NaiveDateTime};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::convert::TryFrom;
+use zcash_client_backend::data_api::WalletRead;
+use zcash_primitives::memo::{Memo, MemoBytes};
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Received => write!(f, "received"),
+            Direction::Sent => write!(f, "sent"),
+        }
+    }
+}
+
+/// A received note or a transaction that spent notes of the account
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    pub direction: Direction,
+    /// Height of the block that mined the transaction
+    pub height: Option<u32>,
+    /// Block time in seconds since the epoch
+    pub timestamp: Option<i64>,
+    pub txid: String,
+    /// In zatoshis. For a sent transaction, the notes spent minus the change, fee included
+    pub amount: u64,
+    pub memo: Option<String>,
+    /// Whether a received note has been spent. Not set for sent transactions
+    pub spent: Option<bool>,
+    /// Mined at the anchor height or before. More recent blocks may still be reorganized
+    pub confirmed: bool,
+}
+
+impl HistoryEntry {
+    pub fn date(&self) -> String {
+        self.timestamp
+            .map(|t| NaiveDateTime::from_timestamp(t, 0).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Lists the notes received by the account, except change, and the transactions that spent them,
+/// oldest first. `from` and `to` are inclusive
+pub fn get_history(
+    opts: &Opt,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> Result<Vec<HistoryEntry>> {
+    let wallet_db = opts.wallet_db()?;
+    if !wallet_db.get_extended_full_viewing_keys()?.contains_key(&opts.account) {
+        return Err(WalletError::AccountNotFound(opts.account.0).into());
+    }
+    let anchor_height = confirmed_height(&wallet_db)?;
+    let connection = Connection::open(opts.data_path())?;
+    let account = opts.account.0;
+    let mut history = Vec::new();
+
+    let mut statement = connection.prepare(
+        "SELECT t.txid, t.block, b.time, n.value, n.memo, n.spent IS NOT NULL \
+         FROM received_notes n JOIN transactions t ON t.id_tx = n.tx \
+         LEFT JOIN blocks b ON b.height = t.block \
+         WHERE n.account = ?1 AND NOT n.is_change",
+    )?;
+    let received = statement.query_map(params![account], |row| {
+        Ok(HistoryEntry {
+            direction: Direction::Received,
+            txid: txid_to_string(row.get(0)?),
+            height: row.get(1)?,
+            timestamp: row.get(2)?,
+            amount: row.get::<_, i64>(3)? as u64,
+            memo: row.get::<_, Option<Vec<u8>>>(4)?.and_then(|memo| memo_to_string(&memo)),
+            spent: Some(row.get(5)?),
+            confirmed: false,
+        })
+    })?;
+    for entry in received {
+        history.push(entry?);
+    }
+
+    let mut statement = connection.prepare(
+        "SELECT t.txid, t.block, b.time, \
+         (SELECT SUM(value) FROM received_notes WHERE spent = t.id_tx AND account = ?1) - \
+         (SELECT COALESCE(SUM(value), 0) FROM received_notes WHERE tx = t.id_tx AND account = ?1 AND is_change) \
+         FROM transactions t LEFT JOIN blocks b ON b.height = t.block \
+         WHERE t.id_tx IN (SELECT spent FROM received_notes WHERE account = ?1 AND spent IS NOT NULL)",
+    )?;
+    let sent = statement.query_map(params![account], |row| {
+        Ok(HistoryEntry {
+            direction: Direction::Sent,
+            txid: txid_to_string(row.get(0)?),
+            height: row.get(1)?,
+            timestamp: row.get(2)?,
+            amount: row.get::<_, i64>(3)? as u64,
+            memo: None,
+            spent: None,
+            confirmed: false,
+        })
+    })?;
+    for entry in sent {
+        history.push(entry?);
+    }
+
+    for entry in history.iter_mut() {
+        entry.confirmed = entry.height.map_or(false, |height| height <= anchor_height);
+    }
+
+    filter_by_date(&mut history, from, to);
+    sort_history(&mut history);
+    Ok(history)
+}
+
+/// Keeps the entries mined between `from` and `to`, both inclusive
+fn filter_by_date(history: &mut Vec<HistoryEntry>, from: Option<NaiveDate>, to: Option<NaiveDate>) {
+    let from = from.map(|d| d.and_hms(0, 0, 0).timestamp());
+    let to = to.map(|d| d.and_hms(23, 59, 59).timestamp());
+    history.retain(|entry| match entry.timestamp {
+        Some(t) => from.map_or(true, |from| t >= from) && to.map_or(true, |to| t <= to),
+        // Not mined yet: newer than any date in the past
+        None => to.is_none(),
+    });
+}
+
+/// Oldest first, received notes before the transactions that spend in the same block.
+/// Unmined transactions come last
+fn sort_history(history: &mut [HistoryEntry]) {
+    history.sort_by_key(|entry| {
+        (entry.height.unwrap_or(u32::MAX), entry.direction == Direction::Sent)
+    });
+}
+
+/// Writes the history as CSV, amounts in the unit of the options
+pub fn write_history_csv<W: std::io::Write>(
+    mut w: W,
+    history: &[HistoryEntry],
+    opts: &Opt,
+) -> Result<()> {
+    let unit = &opts.unit;
+    writeln!(w, "direction,height,date,txid,amount ({}),memo,spent,confirmed", unit)?;
+    for entry in history.iter() {
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{},{}",
+            entry.direction,
+            entry.height.map(|h| h.to_string()).unwrap_or_default(),
+            entry.date(),
+            entry.txid,
+            unit.from_satoshis(entry.amount),
+            csv_field(entry.memo.as_deref().unwrap_or_default()),
+            entry.spent.map(|s| s.to_string()).unwrap_or_default(),
+            entry.confirmed,
+        )?;
+    }
+    Ok(())
+}
+
+/// Transaction ids are stored in internal byte order and displayed reversed
+fn txid_to_string(mut txid: Vec<u8>) -> String {
+    txid.reverse();
+    hex::encode(txid)
+}
+
+fn memo_to_string(memo: &[u8]) -> Option<String> {
+    let decoded = MemoBytes::from_bytes(memo)
+        .ok()
+        .and_then(|memo| Memo::try_from(memo).ok());
+    match decoded {
+        Some(Memo::Empty) => None,
+        Some(Memo::Text(text)) => Some(String::from(&*text)),
+        _ => {
+            // Not text: show the bytes without the zero padding
+            let end = memo.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+            Some(hex::encode(&memo[..end]))
+        }
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(direction: Direction, height: Option<u32>, timestamp: Option<i64>) -> HistoryEntry {
+        HistoryEntry {
+            direction,
+            height,
+            timestamp,
+            txid: String::new(),
+            amount: 0,
+            memo: None,
+            spent: None,
+            confirmed: false,
+        }
+    }
+
+    fn timestamp(y: i32, m: u32, d: u32, h: u32, mi: u32, s: u32) -> Option<i64> {
+        Some(NaiveDate::from_ymd(y, m, d).and_hms(h, mi, s).timestamp())
+    }
+
+    fn heights(history: &[HistoryEntry]) -> Vec<Option<u32>> {
+        history.iter().map(|entry| entry.height).collect()
+    }
+
+    fn test_history() -> Vec<HistoryEntry> {
+        vec![
+            entry(Direction::Received, Some(1), timestamp(2020, 12, 31, 23, 59, 59)),
+            entry(Direction::Received, Some(2), timestamp(2021, 1, 1, 0, 0, 0)),
+            entry(Direction::Sent, Some(3), timestamp(2021, 1, 31, 23, 59, 59)),
+            entry(Direction::Received, Some(4), timestamp(2021, 2, 1, 0, 0, 0)),
+            entry(Direction::Sent, None, None),
+        ]
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("thanks"), "thanks");
+        assert_eq!(csv_field("rent, january"), "\"rent, january\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("two\r\nlines"), "\"two\r\nlines\"");
+    }
+
+    #[test]
+    fn memos() {
+        let padded = |bytes: &[u8]| {
+            let mut memo = bytes.to_vec();
+            memo.resize(512, 0);
+            memo
+        };
+        assert_eq!(memo_to_string(&padded(&[0xF6])), None);
+        assert_eq!(
+            memo_to_string(&padded(b"Hello, world")).as_deref(),
+            Some("Hello, world")
+        );
+        // Arbitrary data and text that is not UTF-8 are shown in hex
+        assert_eq!(
+            memo_to_string(&padded(&[0xFF, 1, 2, 0, 3])).as_deref(),
+            Some("ff01020003")
+        );
+        assert_eq!(memo_to_string(&padded(&[0xC3, 0x28])).as_deref(), Some("c328"));
+    }
+
+    #[test]
+    fn no_filter_keeps_everything() {
+        let mut history = test_history();
+        filter_by_date(&mut history, None, None);
+        assert_eq!(history.len(), 5);
+    }
+
+    #[test]
+    fn dates_are_inclusive() {
+        let mut history = test_history();
+        filter_by_date(
+            &mut history,
+            Some(NaiveDate::from_ymd(2021, 1, 1)),
+            Some(NaiveDate::from_ymd(2021, 1, 31)),
+        );
+        assert_eq!(heights(&history), vec![Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn unmined_entries_are_newer_than_any_date() {
+        let mut history = test_history();
+        filter_by_date(&mut history, Some(NaiveDate::from_ymd(2021, 2, 1)), None);
+        assert_eq!(heights(&history), vec![Some(4), None]);
+
+        let mut history = test_history();
+        filter_by_date(&mut history, None, Some(NaiveDate::from_ymd(2030, 1, 1)));
+        assert_eq!(heights(&history), vec![Some(1), Some(2), Some(3), Some(4)]);
+    }
+
+    #[test]
+    fn oldest_first_and_unmined_last() {
+        let mut history = vec![
+            entry(Direction::Sent, None, None),
+            entry(Direction::Sent, Some(7), None),
+            entry(Direction::Received, Some(9), None),
+            entry(Direction::Received, Some(7), None),
+            entry(Direction::Received, None, None),
+            entry(Direction::Received, Some(3), None),
+        ];
+        sort_history(&mut history);
+        let order: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.height, entry.direction.to_string()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (Some(3), "received".to_string()),
+                (Some(7), "received".to_string()),
+                (Some(7), "sent".to_string()),
+                (Some(9), "received".to_string()),
+                (None, "received".to_string()),
+                (None, "sent".to_string()),
+            ]
+        );
+    }
+}
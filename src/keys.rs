@@ -1,13 +1,18 @@
-use crate::Result;
+use crate::{network::Params, Result, WalletError};
+use crate::sign::decode_spending_key;
 use bip39::{Language, Mnemonic, Seed};
 use rand::rngs::OsRng;
 use rand::RngCore;
 use zcash_client_backend::encoding::{encode_extended_full_viewing_key, encode_extended_spending_key, encode_payment_address};
-use crate::constants::{HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, HRP_SAPLING_EXTENDED_SPENDING_KEY, HRP_SAPLING_PAYMENT_ADDRESS, COIN_TYPE};
+use zcash_primitives::consensus::Parameters;
 use zcash_primitives::zip32::{ChildIndex, ExtendedSpendingKey, ExtendedFullViewingKey};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Serialize)]
+const HARDENED_KEY_START: u32 = 0x8000_0000;
+/// Common to the spending key encodings of every network
+const SPENDING_KEY_PREFIX: &str = "secret-extended-key-";
+
+#[derive(Serialize, Deserialize)]
 pub struct Keys {
     pub phrase: String,
     pub derivation_path: String,
@@ -16,30 +21,151 @@ pub struct Keys {
     pub address: String,
 }
 
-pub fn generate_key() -> Result<Keys> {
+/// Generates a new seed phrase and derives the keys of the given account.
+/// The passphrase is the optional BIP-39 "25th word"
+pub fn generate_key(network: &Params, passphrase: &str, account_index: u32) -> Result<Keys> {
     let mut entropy = [0u8; 32];
     OsRng.fill_bytes(&mut entropy);
     let mnemonic = Mnemonic::from_entropy(&entropy, Language::English)?;
+    keys_from_mnemonic(network, &mnemonic, passphrase, account_index)
+}
+
+/// Re-derives the keys of an account from an existing seed phrase
+pub fn restore_key(network: &Params, phrase: &str, passphrase: &str, account_index: u32) -> Result<Keys> {
+    let mnemonic = parse_phrase(phrase)?;
+    keys_from_mnemonic(network, &mnemonic, passphrase, account_index)
+}
+
+fn keys_from_mnemonic(network: &Params, mnemonic: &Mnemonic, passphrase: &str, account_index: u32) -> Result<Keys> {
     let phrase = mnemonic.phrase();
-    let seed = Seed::new(&mnemonic, "");
-    let master = ExtendedSpendingKey::master(seed.as_bytes());
-    let path = [
-        ChildIndex::Hardened(32),
-        ChildIndex::Hardened(COIN_TYPE),
-        ChildIndex::Hardened(0),
-    ];
-    let extsk = ExtendedSpendingKey::from_path(&master, &path);
-    let spending_key = encode_extended_spending_key(HRP_SAPLING_EXTENDED_SPENDING_KEY, &extsk);
+    let extsk = derive_spending_key(network, mnemonic, passphrase, account_index)?;
+    let spending_key = encode_extended_spending_key(network.hrp_sapling_extended_spending_key(), &extsk);
     let fvk = ExtendedFullViewingKey::from(&extsk);
-    let viewing_key = encode_extended_full_viewing_key(HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, &fvk);
+    let viewing_key = encode_extended_full_viewing_key(network.hrp_sapling_extended_full_viewing_key(), &fvk);
     let (_, payment_address) = extsk.default_address().unwrap();
-    let address = encode_payment_address(HRP_SAPLING_PAYMENT_ADDRESS, &payment_address);
+    let address = encode_payment_address(network.hrp_sapling_payment_address(), &payment_address);
 
     Ok(Keys {
         phrase: phrase.to_string(),
-        derivation_path: format!("m/32'/{}'/0'", COIN_TYPE),
+        derivation_path: derivation_path(network, account_index),
         spending_key,
         viewing_key,
         address,
     })
 }
+
+/// Parses a seed phrase and checks its words and its BIP-39 checksum.
+/// Errors give the positions of the invalid words, never the words
+pub(crate) fn parse_phrase(phrase: &str) -> Result<Mnemonic> {
+    let phrase = phrase.to_lowercase();
+    let words: Vec<&str> = phrase.split_whitespace().collect();
+    let wordmap = Language::English.wordmap();
+    let invalid_positions: Vec<usize> = words
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| wordmap.get_bits(w).is_err())
+        .map(|(i, _)| i + 1)
+        .collect();
+    if !invalid_positions.is_empty() {
+        return Err(WalletError::InvalidSeedWords(invalid_positions).into());
+    }
+    if ![12, 15, 18, 21, 24].contains(&words.len()) {
+        return Err(WalletError::InvalidSeedLength(words.len()).into());
+    }
+    let mnemonic = Mnemonic::from_phrase(&words.join(" "), Language::English)
+        .map_err(|_| WalletError::InvalidSeedChecksum)?;
+    Ok(mnemonic)
+}
+
+pub fn derivation_path(network: &Params, account_index: u32) -> String {
+    format!("m/32'/{}'/{}'", network.coin_type(), account_index)
+}
+
+/// Derives the spending key at m/32'/coin_type'/account' (ZIP 32).
+/// The account index must leave room for the hardened bit
+fn derive_spending_key(network: &Params, mnemonic: &Mnemonic, passphrase: &str, account_index: u32) -> Result<ExtendedSpendingKey> {
+    if account_index >= HARDENED_KEY_START {
+        return Err(WalletError::InvalidAccountIndex(account_index).into());
+    }
+    let seed = Seed::new(mnemonic, passphrase);
+    let master = ExtendedSpendingKey::master(seed.as_bytes());
+    let path = [
+        ChildIndex::Hardened(32),
+        ChildIndex::Hardened(network.coin_type()),
+        ChildIndex::Hardened(account_index),
+    ];
+    Ok(ExtendedSpendingKey::from_path(&master, &path))
+}
+
+/// Gets the spending key from either an encoded spending key or a seed phrase.
+/// The passphrase and the account index only apply to seed phrases
+pub fn spending_key_from_secret(network: &Params, secret: &str, passphrase: &str, account_index: u32) -> Result<ExtendedSpendingKey> {
+    let secret = secret.trim();
+    if secret.starts_with(network.hrp_sapling_extended_spending_key()) {
+        return decode_spending_key(network, secret);
+    }
+    // A spending key of another network must not be reported as a seed phrase with invalid words
+    if secret.starts_with(SPENDING_KEY_PREFIX) {
+        return Err(WalletError::SpendingKeyNetwork(network.network_type.to_string()).into());
+    }
+    let mnemonic = parse_phrase(secret)?;
+    derive_spending_key(network, &mnemonic, passphrase, account_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkType;
+
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn wallet_error(result: Result<impl Sized>) -> WalletError {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(error) => error.downcast::<WalletError>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn invalid_words_are_reported_by_position() {
+        let phrase = PHRASE.replacen("abandon", "hunter2", 1).replace("about", "secretword");
+        let error = wallet_error(parse_phrase(&phrase));
+        assert!(matches!(&error, WalletError::InvalidSeedWords(positions) if positions == &[1, 12]));
+        let message = error.to_string();
+        assert!(!message.contains("hunter2"));
+        assert!(!message.contains("secretword"));
+    }
+
+    #[test]
+    fn phrase_length_and_checksum() {
+        assert!(parse_phrase(PHRASE).is_ok());
+        assert!(parse_phrase(&PHRASE.to_uppercase()).is_ok());
+        let error = wallet_error(parse_phrase(&PHRASE.replace(" about", "")));
+        assert!(matches!(error, WalletError::InvalidSeedLength(11)));
+        let error = wallet_error(parse_phrase(&PHRASE.replace("about", "abandon")));
+        assert!(matches!(error, WalletError::InvalidSeedChecksum));
+    }
+
+    #[test]
+    fn spending_key_of_another_network_is_not_echoed() {
+        let test = Params::new(NetworkType::Test);
+        let main = Params::new(NetworkType::Main);
+        let keys = restore_key(&test, PHRASE, "", 0).unwrap();
+        let error = wallet_error(spending_key_from_secret(&main, &keys.spending_key, "", 0));
+        assert!(matches!(error, WalletError::SpendingKeyNetwork(_)));
+        assert!(!error.to_string().contains(&keys.spending_key));
+        assert!(spending_key_from_secret(&test, &keys.spending_key, "", 0).is_ok());
+    }
+
+    #[test]
+    fn invalid_spending_key_is_not_echoed() {
+        let test = Params::new(NetworkType::Test);
+        let keys = restore_key(&test, PHRASE, "", 0).unwrap();
+        let mut corrupted = keys.spending_key.clone();
+        corrupted.pop();
+        corrupted.push(if keys.spending_key.ends_with('q') { 'p' } else { 'q' });
+        let error = wallet_error(spending_key_from_secret(&test, &corrupted, "", 0));
+        assert!(matches!(error, WalletError::InvalidSpendingKey));
+        assert!(!error.to_string().contains(&corrupted));
+    }
+}
@@ -0,0 +1,164 @@
+use crate::keys::Keys;
+use crate::{Result, WalletError};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use scrypt::{scrypt, ScryptParams};
+use serde::{Deserialize, Serialize};
+
+pub const KEYSTORE_VERSION: u32 = 1;
+
+// scrypt cost parameters: N = 2^15, r = 8, p = 1 (about 32 MB of memory)
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Keys encrypted with a password.
+///
+/// The encryption key is derived from the password with scrypt and
+/// the keys are encrypted with XChaCha20-Poly1305. All the parameters
+/// needed to decrypt are stored alongside the ciphertext.
+#[derive(Serialize, Deserialize)]
+pub struct Keystore {
+    version: u32,
+    kdf: String,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: String,
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+    /// Kept in clear so that the keystore can be identified without the password
+    viewing_key: String,
+}
+
+fn derive_key(password: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> Result<Key> {
+    let params = ScryptParams::new(log_n, r, p)
+        .map_err(|_| WalletError::Keystore("Invalid scrypt parameters".to_string()))?;
+    let mut key = [0u8; 32];
+    scrypt(password.as_bytes(), salt, &params, &mut key)
+        .map_err(|_| WalletError::Keystore("Key derivation failed".to_string()))?;
+    Ok(Key::clone_from_slice(&key))
+}
+
+pub fn encrypt_keys(keys: &Keys, password: &str) -> Result<String> {
+    encrypt_keys_with_cost(keys, password, SCRYPT_LOG_N)
+}
+
+fn encrypt_keys_with_cost(keys: &Keys, password: &str, log_n: u8) -> Result<String> {
+    let mut salt = [0u8; 32];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; 24];
+    OsRng.fill_bytes(&mut nonce);
+
+    let key = derive_key(password, &salt, log_n, SCRYPT_R, SCRYPT_P)?;
+    let cipher = XChaCha20Poly1305::new(&key);
+    let plaintext = serde_json::to_vec(keys)?;
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext.as_ref())
+        .map_err(|_| WalletError::Keystore("Encryption failed".to_string()))?;
+
+    let keystore = Keystore {
+        version: KEYSTORE_VERSION,
+        kdf: "scrypt".to_string(),
+        log_n,
+        r: SCRYPT_R,
+        p: SCRYPT_P,
+        salt: hex::encode(salt),
+        cipher: "xchacha20poly1305".to_string(),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+        viewing_key: keys.viewing_key.clone(),
+    };
+    let keystore = serde_json::to_string_pretty(&keystore)?;
+    Ok(keystore)
+}
+
+pub fn decrypt_keys(keystore: &str, password: &str) -> Result<Keys> {
+    let keystore: Keystore = serde_json::from_str(keystore)
+        .map_err(|_| WalletError::Keystore("Could not parse keystore file".to_string()))?;
+    if keystore.version != KEYSTORE_VERSION
+        || keystore.kdf != "scrypt"
+        || keystore.cipher != "xchacha20poly1305"
+    {
+        return Err(WalletError::Keystore("Unsupported keystore format".to_string()).into());
+    }
+    let salt = hex::decode(&keystore.salt)?;
+    let nonce = hex::decode(&keystore.nonce)?;
+    if nonce.len() != 24 {
+        return Err(WalletError::Keystore("Invalid nonce".to_string()).into());
+    }
+    let ciphertext = hex::decode(&keystore.ciphertext)?;
+
+    let key = derive_key(password, &salt, keystore.log_n, keystore.r, keystore.p)?;
+    let cipher = XChaCha20Poly1305::new(&key);
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| WalletError::Keystore("Wrong password".to_string()))?;
+    let keys: Keys = serde_json::from_slice(&plaintext)
+        .map_err(|_| WalletError::Keystore("Could not parse keys".to_string()))?;
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_keys() -> Keys {
+        Keys {
+            phrase: "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about".to_string(),
+            derivation_path: "m/32'/1'/0'".to_string(),
+            spending_key: "secret-extended-key-test1".to_string(),
+            viewing_key: "zxviewtestsapling1".to_string(),
+            address: "ztestsapling1".to_string(),
+        }
+    }
+
+    // Cheaper than the real parameters, to keep the tests fast
+    const TEST_LOG_N: u8 = 10;
+
+    fn keystore_error(keystore: &str, password: &str) -> String {
+        match decrypt_keys(keystore, password).err().unwrap().downcast::<WalletError>() {
+            Ok(WalletError::Keystore(message)) => message,
+            _ => panic!("expected a keystore error"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let keystore = encrypt_keys_with_cost(&test_keys(), "correct horse", TEST_LOG_N).unwrap();
+        assert!(!keystore.contains("abandon"));
+        assert!(!keystore.contains("secret-extended-key"));
+        let keys = decrypt_keys(&keystore, "correct horse").unwrap();
+        assert_eq!(keys.phrase, test_keys().phrase);
+        assert_eq!(keys.spending_key, test_keys().spending_key);
+        assert_eq!(keys.viewing_key, test_keys().viewing_key);
+    }
+
+    #[test]
+    fn wrong_password() {
+        let keystore = encrypt_keys_with_cost(&test_keys(), "correct horse", TEST_LOG_N).unwrap();
+        assert_eq!(keystore_error(&keystore, "correct horse "), "Wrong password");
+        assert_eq!(keystore_error(&keystore, ""), "Wrong password");
+    }
+
+    #[test]
+    fn tampered_keystore() {
+        let keystore = encrypt_keys_with_cost(&test_keys(), "correct horse", TEST_LOG_N).unwrap();
+        let mut parsed: Keystore = serde_json::from_str(&keystore).unwrap();
+        let mut ciphertext = hex::decode(&parsed.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        parsed.ciphertext = hex::encode(ciphertext);
+        let tampered = serde_json::to_string(&parsed).unwrap();
+        assert_eq!(keystore_error(&tampered, "correct horse"), "Wrong password");
+
+        let mut parsed: Keystore = serde_json::from_str(&keystore).unwrap();
+        parsed.version = KEYSTORE_VERSION + 1;
+        let newer = serde_json::to_string(&parsed).unwrap();
+        assert_eq!(keystore_error(&newer, "correct horse"), "Unsupported keystore format");
+
+        assert_eq!(keystore_error("{}", "correct horse"), "Could not parse keystore file");
+    }
+}
@@ -1,9 +1,15 @@
 use bigdecimal::{BigDecimal, ToPrimitive};
 use serde::{Deserialize, Serialize};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, NO_PARAMS};
+use std::path::PathBuf;
 use std::str::FromStr;
+use zcash_client_sqlite::WalletDB;
+use zcash_client_backend::data_api::WalletRead;
 use zcash_client_backend::wallet::AccountId;
 use thiserror::Error;
 use crate::grpc::compact_tx_streamer_client::CompactTxStreamerClient;
+use crate::config::TlsConfig;
+use crate::network::Params;
 
 pub const DATA_PATH: &str = "data.sqlite3";
 pub const CACHE_PATH: &str = "cache.sqlite3";
@@ -15,14 +21,23 @@ pub mod grpc {
 pub mod account;
 pub mod chain;
 pub mod checkpoint;
+pub mod config;
+pub mod envelope;
+pub mod history;
 pub mod keys;
+pub mod keystore;
+pub mod network;
+pub mod pszt;
+pub mod qr;
+pub mod review;
+pub mod shares;
 pub mod sign;
 pub mod transact;
 
-pub const ACCOUNT: AccountId = AccountId(0);
 pub use anyhow::Result as Result;
-use tonic::transport::{ClientTlsConfig, Channel};
+use tonic::transport::{Certificate, ClientTlsConfig, Channel};
 use zcash_client_backend::data_api::wallet::ANCHOR_OFFSET;
+use zcash_primitives::transaction::components::amount::MAX_MONEY;
 
 #[derive(Debug, Clone)]
 pub enum ZECUnit {
@@ -32,14 +47,14 @@ pub enum ZECUnit {
 }
 
 impl FromStr for ZECUnit {
-    type Err = std::io::Error;
+    type Err = WalletError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         Ok(match s {
             "Zat" => ZECUnit::Zat,
             "MilliZec" => ZECUnit::MilliZec,
             "Zec" => ZECUnit::Zec,
-            _ => panic!("Unit must be one of Zat, MilliZec or Zec"),
+            _ => return Err(WalletError::UnknownUnit(s.to_string())),
         })
     }
 }
@@ -55,14 +70,22 @@ impl std::fmt::Display for ZECUnit {
 }
 
 impl ZECUnit {
-    pub fn to_satoshis(&self, amount: &str) -> u64 {
-        let u = BigDecimal::from_str(amount).unwrap();
+    /// Converts an amount in this unit to zatoshis. It must be a whole number of zatoshis,
+    /// between 0 and the maximum supply
+    pub fn to_satoshis(&self, amount: &str) -> std::result::Result<u64, WalletError> {
+        let invalid = || WalletError::InvalidAmount(amount.to_string());
+        let u = BigDecimal::from_str(amount).map_err(|_| invalid())?;
         let r = match self {
             ZECUnit::Zec => u * BigDecimal::from(100_000_000),
             ZECUnit::MilliZec => u * BigDecimal::from(100_000),
             ZECUnit::Zat => u,
         };
-        r.to_u64().unwrap()
+        if r.with_scale(0) != r {
+            return Err(invalid());
+        }
+        r.to_u64()
+            .filter(|&satoshis| satoshis <= MAX_MONEY as u64)
+            .ok_or_else(invalid)
     }
 
     pub fn from_satoshis(&self, amount: u64) -> String {
@@ -79,13 +102,87 @@ impl ZECUnit {
 pub struct Opt {
     pub lightnode_url: String,
     pub unit: ZECUnit,
+    pub account: AccountId,
+    pub data_dir: PathBuf,
+    pub data_file: PathBuf,
+    pub cache_file: PathBuf,
+    pub network: Params,
+    /// Fee in zatoshis
+    pub fee: u64,
+    pub tls: TlsConfig,
+}
+
+impl Opt {
+    pub fn data_path(&self) -> PathBuf {
+        self.data_dir.join(&self.data_file)
+    }
+
+    pub fn cache_path(&self) -> PathBuf {
+        self.data_dir.join(&self.cache_file)
+    }
+
+    pub fn wallet_db(&self) -> Result<WalletDB<Params>> {
+        self.check_network()?;
+        Ok(WalletDB::for_path(self.data_path(), self.network.clone())?)
+    }
+
+    /// The network is recorded in the wallet database by init-db and must match --network afterwards.
+    /// Does not create the database nor write to it
+    pub fn check_network(&self) -> Result<()> {
+        let network = self.network.network_type.to_string();
+        let data_path = self.data_path();
+        if !data_path.exists() {
+            return Err(WalletError::WalletNotFound(data_path.display().to_string()).into());
+        }
+        let connection = Connection::open_with_flags(&data_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let has_meta: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'coldwallet_meta'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        let wallet_network: Option<String> = if has_meta > 0 {
+            connection
+                .query_row(
+                    "SELECT value FROM coldwallet_meta WHERE key = 'network'",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .optional()?
+        } else {
+            None
+        };
+        let wallet_network = wallet_network.ok_or(WalletError::NetworkNotRecorded)?;
+        if wallet_network != network {
+            return Err(WalletError::WalletNetwork(wallet_network, network).into());
+        }
+        Ok(())
+    }
+
+    /// Records the network of --network in the wallet database, unless it already has one
+    pub fn record_network(&self) -> Result<()> {
+        let network = self.network.network_type.to_string();
+        let connection = Connection::open(self.data_path())?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS coldwallet_meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL)",
+            NO_PARAMS,
+        )?;
+        connection.execute(
+            "INSERT OR IGNORE INTO coldwallet_meta(key, value) VALUES ('network', ?1)",
+            params![network],
+        )?;
+        self.check_network()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Tx {
     height: i64,
     inputs: Vec<TxIn>,
-    output: Option<TxOut>,
+    outputs: Vec<TxOut>,
+    change: Option<TxOut>,
+    fee: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -98,15 +195,33 @@ pub struct TxIn {
     witness: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressType {
+    Shielded,
+    Transparent,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxOut {
+    addr_type: AddressType,
     addr: String,
     amount: u64,
     ovk: String,
+    memo: Option<String>,
 }
 
 pub const MAX_REORG_DEPTH: u64 = ANCHOR_OFFSET as u64;
 
+/// Anchor height of the next transaction, as in `prepare_tx`. Notes mined at this height
+/// or before are confirmed and spendable. More recent blocks may still be reorganized
+pub fn confirmed_height(wallet_db: &WalletDB<Params>) -> Result<u32> {
+    Ok(wallet_db
+        .get_target_and_anchor_heights()?
+        .map(|(_, anchor_height)| u32::from(anchor_height))
+        .unwrap_or(0))
+}
+
 #[derive(Error, Debug, Clone)]
 pub enum WalletError {
     #[error("Not enough funds: {} < {} {}", .2.from_satoshis(*.0), .2.from_satoshis(*.1), .2)]
@@ -119,45 +234,153 @@ pub enum WalletError {
     TxParse,
     #[error("Account not initialized. Did you use init-account?")]
     AccountNotInitialized,
+    #[error("Account {} not found", .0)]
+    AccountNotFound(u32),
+    #[error("Birthday height {} is before the first block of the wallet {}. Create a new wallet instead", .0, .1)]
+    BirthdayBeforeWallet(u64, u32),
     #[error("Failed to submit transaction. Error code {}, Error Message {}", .0, .1)]
     Submit(i32, String),
+    #[error("Chain reorganization deeper than {} blocks below height {}. Delete the cache and the wallet database and import the accounts again", .1, .0)]
+    ReorgTooDeep(u64, u64),
+    #[error("The chain kept changing during the sync. Gave up after {} attempts", .0)]
+    ChainUnstable(usize),
+    #[error("Sync interrupted. Blocks up to {} are saved. Run sync again to resume", .0)]
+    Interrupted(u64),
+    #[error("Invalid recipient on line {}: {}. Expected address,amount", .0, .1)]
+    InvalidRecipient(usize, String),
+    #[error("Invalid amount {}. It must be a positive number with at most 8 decimals in ZEC", .0)]
+    InvalidAmount(String),
+    #[error("Change address {} does not belong to the spending key", .0)]
+    ChangeAddress(String),
+    #[error("Memo is {} bytes long. It must be at most 512 bytes", .0)]
+    MemoTooLong(usize),
+    #[error("Invalid memo")]
+    InvalidMemo,
+    #[error("Cannot attach a memo to the transparent address {}", .0)]
+    TransparentMemo(String),
+    #[error("Inputs ({}) do not match outputs, change and fee ({}) {}", .2.from_satoshis(*.0), .2.from_satoshis(*.1), .2)]
+    Unbalanced(u64, u64, ZECUnit),
+    #[error("Invalid fee {} zatoshis", .0)]
+    InvalidFee(u64),
+    #[error("Fee {} zatoshis is not supported. Transactions pay the standard fee of {} zatoshis", .0, .1)]
+    UnsupportedFee(u64, u64),
+    #[error("Unexpected fee: {} instead of {} {}", .2.from_satoshis(*.0), .2.from_satoshis(*.1), .2)]
+    Fee(u64, u64, ZECUnit),
+    #[error("Transaction file version {} is not supported. This wallet uses version {}", .0, .1)]
+    TxVersion(u32, u32),
+    #[error("Transaction file is for the {} network but this wallet is for the {} network", .0, .1)]
+    WrongNetwork(String, String),
+    #[error("Unknown unit {}. It must be one of Zat, MilliZec or Zec", .0)]
+    UnknownUnit(String),
+    #[error("Unknown network {}. It must be one of main, test or regtest", .0)]
+    UnknownNetwork(String),
+    #[error("The wallet database is for the {} network but the {} network was selected", .0, .1)]
+    WalletNetwork(String, String),
+    #[error("The wallet database does not record its network. Run migrate with the --network of this wallet")]
+    NetworkNotRecorded,
+    #[error("Wallet database {} not found. Check the data directory or run init-db", .0)]
+    WalletNotFound(String),
+    #[error("Transaction file checksum mismatch. The file is corrupted or truncated")]
+    Checksum,
+    #[error("Transaction file authentication failed. It was not made with the viewing key of this spending key")]
+    Mac,
+    #[error("Invalid PSZT: {}", .0)]
+    Pszt(String),
+    #[error("QR code error: {}", .0)]
+    Qr(String),
+    #[error("Invalid seed phrase. The words at these positions are not in the BIP-39 English word list: {}",
+        .0.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", "))]
+    InvalidSeedWords(Vec<usize>),
+    #[error("Invalid spending key")]
+    InvalidSpendingKey,
+    #[error("The spending key is not for the {} network", .0)]
+    SpendingKeyNetwork(String),
+    #[error("Invalid seed phrase. It has {} words instead of 12, 15, 18, 21 or 24", .0)]
+    InvalidSeedLength(usize),
+    #[error("Invalid seed phrase. The checksum does not match: a word is wrong or the words are not in the right order")]
+    InvalidSeedChecksum,
+    #[error("Account index {} is too large. It must be below 2147483648", .0)]
+    InvalidAccountIndex(u32),
+    #[error("Keystore error: {}", .0)]
+    Keystore(String),
+    #[error("Keystore {} already exists. Choose another file name", .0)]
+    KeystoreExists(String),
+    #[error("Secret sharing error: {}", .0)]
+    Shares(String),
+    #[error("Passphrases do not match")]
+    PassphraseMismatch,
+    #[error("Transaction not confirmed")]
+    Cancelled,
+    #[error("No recipient. Pass an address and an amount or a CSV file")]
+    NoRecipient,
+}
+
+impl WalletError {
+    /// Stable identifier of the error for the JSON output
+    pub fn code(&self) -> &'static str {
+        match self {
+            WalletError::NotEnoughFunds(..) => "not_enough_funds",
+            WalletError::Decode(_) => "decode",
+            WalletError::Prover => "prover",
+            WalletError::TxParse => "tx_parse",
+            WalletError::AccountNotInitialized => "account_not_initialized",
+            WalletError::AccountNotFound(_) => "account_not_found",
+            WalletError::BirthdayBeforeWallet(..) => "birthday_before_wallet",
+            WalletError::Submit(..) => "submit",
+            WalletError::ReorgTooDeep(..) => "reorg_too_deep",
+            WalletError::ChainUnstable(_) => "chain_unstable",
+            WalletError::Interrupted(_) => "interrupted",
+            WalletError::InvalidRecipient(..) => "invalid_recipient",
+            WalletError::InvalidAmount(_) => "invalid_amount",
+            WalletError::ChangeAddress(_) => "change_address",
+            WalletError::MemoTooLong(_) => "memo_too_long",
+            WalletError::InvalidMemo => "invalid_memo",
+            WalletError::TransparentMemo(_) => "transparent_memo",
+            WalletError::Unbalanced(..) => "unbalanced",
+            WalletError::InvalidFee(_) => "invalid_fee",
+            WalletError::UnsupportedFee(..) => "unsupported_fee",
+            WalletError::Fee(..) => "fee",
+            WalletError::TxVersion(..) => "tx_version",
+            WalletError::WrongNetwork(..) => "wrong_network",
+            WalletError::UnknownUnit(_) => "unknown_unit",
+            WalletError::UnknownNetwork(_) => "unknown_network",
+            WalletError::WalletNetwork(..) => "wallet_network",
+            WalletError::NetworkNotRecorded => "network_not_recorded",
+            WalletError::WalletNotFound(_) => "wallet_not_found",
+            WalletError::Checksum => "checksum",
+            WalletError::Mac => "mac",
+            WalletError::Pszt(_) => "pszt",
+            WalletError::Qr(_) => "qr",
+            WalletError::InvalidSeedWords(_) => "invalid_seed_words",
+            WalletError::InvalidSpendingKey => "invalid_spending_key",
+            WalletError::SpendingKeyNetwork(_) => "spending_key_network",
+            WalletError::InvalidSeedLength(_) => "invalid_seed_length",
+            WalletError::InvalidSeedChecksum => "invalid_seed_checksum",
+            WalletError::InvalidAccountIndex(_) => "invalid_account_index",
+            WalletError::Keystore(_) => "keystore",
+            WalletError::KeystoreExists(_) => "keystore_exists",
+            WalletError::Shares(_) => "shares",
+            WalletError::PassphraseMismatch => "passphrase_mismatch",
+            WalletError::Cancelled => "cancelled",
+            WalletError::NoRecipient => "no_recipient",
+        }
+    }
 }
 
-async fn connect_lightnode(lightnode_url: String) -> Result<CompactTxStreamerClient<Channel>> {
+async fn connect_lightnode(opts: &Opt) -> Result<CompactTxStreamerClient<Channel>> {
+    let lightnode_url = opts.lightnode_url.clone();
     let mut channel = tonic::transport::Channel::from_shared(lightnode_url.clone())?;
     if lightnode_url.starts_with("https") {
-        let tls = ClientTlsConfig::new();
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ca_certificate) = opts.tls.ca_certificate.as_ref() {
+            let pem = std::fs::read(ca_certificate)?;
+            tls = tls.ca_certificate(Certificate::from_pem(pem));
+        }
+        if let Some(domain_name) = opts.tls.domain_name.as_ref() {
+            tls = tls.domain_name(domain_name.clone());
+        }
         channel = channel.tls_config(tls)?;
     }
     let client = CompactTxStreamerClient::connect(channel).await?;
     Ok(client)
 }
-
-
-#[cfg(not(feature = "mainnet"))]
-pub mod constants {
-    use zcash_primitives::consensus::Network::{self, TestNetwork};
-    use zcash_primitives::constants::testnet;
-
-    pub const NETWORK: Network = TestNetwork;
-    pub const HRP_SAPLING_EXTENDED_SPENDING_KEY: &str = testnet::HRP_SAPLING_EXTENDED_SPENDING_KEY;
-    pub const HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY: &str =
-        testnet::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY;
-    pub const HRP_SAPLING_PAYMENT_ADDRESS: &str = testnet::HRP_SAPLING_PAYMENT_ADDRESS;
-    pub const COIN_TYPE: u32 = testnet::COIN_TYPE;
-    pub const LIGHTNODE_URL: &str = "https://testnet.lightwalletd.com:9067";
-}
-
-#[cfg(feature = "mainnet")]
-pub mod constants {
-    use zcash_primitives::consensus::Network::{self, MainNetwork};
-    use zcash_primitives::constants::mainnet;
-
-    pub const NETWORK: Network = MainNetwork;
-    pub const HRP_SAPLING_EXTENDED_SPENDING_KEY: &str = mainnet::HRP_SAPLING_EXTENDED_SPENDING_KEY;
-    pub const HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY: &str =
-        mainnet::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY;
-    pub const HRP_SAPLING_PAYMENT_ADDRESS: &str = mainnet::HRP_SAPLING_PAYMENT_ADDRESS;
-    pub const COIN_TYPE: u32 = mainnet::COIN_TYPE;
-    pub const LIGHTNODE_URL: &str = "https://mainnet.lightwalletd.com:9067";
-}
@@ -1,24 +1,46 @@
 use clap::Clap;
+use serde::Serialize;
 use std::fs::File;
+use std::path::PathBuf;
+use std::io::Write;
 use zcash_coldwallet::sign::sign_tx;
 use zcash_coldwallet::transact::submit;
 use zcash_coldwallet::{
-    account::{init_account, get_balance},
-    chain::{init_db, sync, scan},
+    account::{add_account, init_account, get_balance, get_ovk, Amounts},
+    chain::{init_db, migrate_db, sync, scan},
     checkpoint::find_height,
+    config::Config,
+    envelope::{open, seal},
+    history::{get_history, write_history_csv},
+    pszt::{extract_pszt, finalize_pszt, sign_pszt, Pszt},
+    qr::{decode_parts, encode, encode_parts, read_png_parts, render_terminal, write_png_parts},
     grpc::RawTransaction,
-    keys::generate_key,
-    transact::prepare_tx,
-    Opt, Result, Tx, WalletError, ZECUnit, constants::LIGHTNODE_URL,
+    keys::{generate_key, restore_key, spending_key_from_secret, Keys},
+    keystore::{decrypt_keys, encrypt_keys},
+    shares::{combine_shares, split_phrase},
+    review::{check_fee, confirm, describe_tx},
+    transact::{prepare_tx, read_payments_csv, Payment},
+    network::{NetworkType, DEFAULT_NETWORK},
+    Opt, Result, WalletError, ZECUnit, CACHE_PATH, DATA_PATH,
 };
 use chrono::NaiveDate;
+use zcash_client_backend::wallet::AccountId;
+use zcash_primitives::transaction::components::amount::DEFAULT_FEE;
 
 #[derive(Clap)]
 struct ZCashColdWallet {
     #[clap(short, long)]
     lightwalletd_url: Option<String>,
-    #[clap(short, long, default_value = "Zec")]
-    unit: ZECUnit,
+    #[clap(short, long)]
+    unit: Option<ZECUnit>,
+    #[clap(short, long, default_value = "0")]
+    account: u32,
+    #[clap(short, long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    #[clap(short, long)]
+    network: Option<NetworkType>,
+    #[clap(long)]
+    json: bool,
     #[clap(subcommand)]
     cmd: Command,
 }
@@ -27,27 +49,124 @@ struct ZCashColdWallet {
 enum Command {
     Generate {
         output_filename: Option<String>,
+        #[clap(long)]
+        passphrase: bool,
+        #[clap(long, default_value = "0")]
+        account_index: u32,
+        #[clap(long)]
+        keystore: Option<String>,
+        #[clap(long)]
+        shares: Option<u8>,
+        #[clap(long)]
+        threshold: Option<u8>,
+    },
+    Restore {
+        output_filename: Option<String>,
+        #[clap(short, long)]
+        seed_file: Option<String>,
+        #[clap(long)]
+        passphrase: bool,
+        #[clap(long, default_value = "0")]
+        account_index: u32,
+        #[clap(long)]
+        keystore: Option<String>,
+    },
+    CombineShares {
+        shares_file: Option<String>,
+        output_filename: Option<String>,
+        #[clap(long)]
+        passphrase: bool,
+        #[clap(long, default_value = "0")]
+        account_index: u32,
+        #[clap(long)]
+        keystore: Option<String>,
     },
     InitDb,
+    Migrate,
     InitAccount {
+        #[clap(required = true, min_values = 1)]
+        viewing_keys: Vec<String>,
+        #[clap(short, long)]
+        birth_day: Option<NaiveDate>,
+    },
+    AddAccount {
         viewing_key: String,
+        #[clap(short, long)]
         birth_day: Option<NaiveDate>,
     },
-    GetBalance,
+    GetBalance {
+        #[clap(long)]
+        all: bool,
+    },
+    History {
+        #[clap(long)]
+        from: Option<NaiveDate>,
+        #[clap(long)]
+        to: Option<NaiveDate>,
+        #[clap(long)]
+        csv: Option<String>,
+    },
     Sync,
     ReIndex,
     PrepareTx {
-        recipient_addr: String,
-        amount: String,
+        recipient_addr: Option<String>,
+        amount: Option<String>,
+        #[clap(long)]
+        csv: Option<String>,
+        #[clap(long)]
+        memo: Option<String>,
+        #[clap(long, conflicts_with = "memo")]
+        memo_hex: Option<String>,
+        #[clap(long)]
+        pszt: bool,
+        #[clap(long)]
+        qr: Option<String>,
+        #[clap(short, long)]
         output_filename: Option<String>,
     },
     Sign {
-        spending_key: String,
         tx_json_file: Option<String>,
         output_filename: Option<String>,
+        #[clap(short, long)]
+        key_file: Option<String>,
+        #[clap(long)]
+        keystore: Option<String>,
+        #[clap(long)]
+        passphrase: bool,
+        #[clap(long)]
+        account_index: Option<u32>,
+        #[clap(long)]
+        qr: Option<String>,
+    },
+    Review {
+        tx_json_file: Option<String>,
+    },
+    Finalize {
+        pszt_file: Option<String>,
+        output_filename: Option<String>,
+    },
+    Extract {
+        pszt_file: Option<String>,
+        output_filename: Option<String>,
     },
     Submit {
         raw_tx_file: Option<String>,
+        #[clap(long)]
+        qr: Vec<String>,
+    },
+    QrEncode {
+        input_file: Option<String>,
+        #[clap(short, long)]
+        png_prefix: Option<String>,
+        #[clap(long, default_value = "200")]
+        fragment_size: usize,
+        #[clap(long, default_value = "0")]
+        extra_parts: usize,
+    },
+    QrDecode {
+        images: Vec<String>,
+        #[clap(short, long)]
+        output_filename: Option<String>,
     },
 }
 
@@ -61,6 +180,147 @@ fn read_from_file(file_name: Option<String>) -> String {
     s.trim_end().to_string()
 }
 
+fn read_bytes_from_file(file_name: Option<String>) -> Result<Vec<u8>> {
+    let mut input: Box<dyn std::io::Read> = match file_name {
+        Some(file_name) => Box::new(File::open(file_name)?),
+        None => Box::new(std::io::stdin()),
+    };
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Prompts for a passphrase or password without echo.
+/// When `confirm` is set, asks twice to catch typos
+fn prompt_secret(name: &str, confirm: bool) -> Result<String> {
+    let secret = rpassword::read_password_from_tty(Some(&format!("{}: ", name)))?;
+    if confirm {
+        let again = rpassword::read_password_from_tty(Some(&format!("Confirm {}: ", name.to_lowercase())))?;
+        if again != secret {
+            return Err(WalletError::PassphraseMismatch.into());
+        }
+    }
+    Ok(secret)
+}
+
+fn prompt_passphrase(confirm: bool) -> Result<String> {
+    prompt_secret("Passphrase", confirm)
+}
+
+#[derive(Serialize)]
+struct KeysOutput<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keystore: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threshold: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shares: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phrase: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spending_key: Option<&'a str>,
+    derivation_path: &'a str,
+    viewing_key: &'a str,
+    address: &'a str,
+}
+
+/// Writes the keys in clear, unless they go to an encrypted keystore or get split into shares.
+/// In that case, only the public part is written
+fn write_keys(
+    keys: &Keys,
+    output_filename: Option<String>,
+    keystore: Option<String>,
+    shares: Option<(u8, u8)>,
+    json: bool,
+) -> Result<()> {
+    let mut output = create_file(output_filename)?;
+    if let Some(keystore) = keystore.as_ref() {
+        let password = prompt_secret("Keystore password", true)?;
+        let mut keystore_file = create_private_file(keystore)?;
+        writeln!(keystore_file, "{}", encrypt_keys(keys, &password)?)?;
+    }
+    let in_clear = keystore.is_none() && shares.is_none();
+    let keys_output = KeysOutput {
+        keystore: keystore.as_deref(),
+        threshold: shares.map(|(threshold, _)| threshold),
+        shares: match shares {
+            Some((threshold, count)) => Some(split_phrase(&keys.phrase, threshold, count)?),
+            None => None,
+        },
+        phrase: Some(keys.phrase.as_str()).filter(|_| in_clear),
+        spending_key: Some(keys.spending_key.as_str()).filter(|_| in_clear),
+        derivation_path: &keys.derivation_path,
+        viewing_key: &keys.viewing_key,
+        address: &keys.address,
+    };
+    if json {
+        writeln!(output, "{}", serde_json::to_string(&keys_output)?)?;
+        return Ok(());
+    }
+    if let Some(keystore) = keys_output.keystore {
+        writeln!(output, "keystore: {}", keystore)?;
+    }
+    if let Some(shares) = keys_output.shares.as_ref() {
+        writeln!(output, "seed shares ({} of {}):", keys_output.threshold.unwrap(), shares.len())?;
+        for share in shares.iter() {
+            writeln!(output, "{}", share)?;
+        }
+    }
+    if let (Some(phrase), Some(spending_key)) = (keys_output.phrase, keys_output.spending_key) {
+        writeln!(output, "seed phrase: {}", phrase)?;
+        writeln!(output, "spending key: {}", spending_key)?;
+    }
+    writeln!(output, "derivation path: {}", keys_output.derivation_path)?;
+    writeln!(output, "viewing key: {}", keys_output.viewing_key)?;
+    writeln!(output, "payment address: {}", keys_output.address)?;
+    Ok(())
+}
+
+fn describe_balance(amounts: &Amounts, unit: &ZECUnit) -> String {
+    format!(
+        "{} (spendable {}, pending {}, locked in unconfirmed spends {})",
+        unit.from_satoshis(amounts.total),
+        unit.from_satoshis(amounts.spendable),
+        unit.from_satoshis(amounts.pending),
+        unit.from_satoshis(amounts.locked),
+    )
+}
+
+/// Prints the result of a command: as JSON with --json, as text otherwise
+fn print_output<T: Serialize>(json: bool, value: &T, text: &str) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(value)?);
+    } else {
+        println!("{}", text);
+    }
+    Ok(())
+}
+
+fn write_qr(data: &[u8], png_prefix: Option<String>) -> Result<()> {
+    if let Some(png_prefix) = png_prefix {
+        for file_name in write_png_parts(&encode(data), &png_prefix)? {
+            eprintln!("QR code written to {}", file_name);
+        }
+    }
+    Ok(())
+}
+
+/// Creates a new file that only the owner can read and write.
+/// An existing file is never replaced: it may be the only copy of other keys
+fn create_private_file(file_name: &str) -> Result<File> {
+    #[cfg(unix)]
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let file = options.open(file_name).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => WalletError::KeystoreExists(file_name.to_string()).into(),
+        _ => anyhow::Error::from(e),
+    })?;
+    Ok(file)
+}
+
 fn create_file(filename: Option<String>) -> Result<Box<dyn std::io::Write>> {
     let output: Box<dyn std::io::Write> = match filename {
         Some(file_name) => Box::new(File::create(file_name)?),
@@ -72,82 +332,474 @@ fn create_file(filename: Option<String>) -> Result<Box<dyn std::io::Write>> {
 /*
 quick help:
 
+--network main|test|regtest applies to every command (default: test)
+--json prints the result or the error as a JSON object
+
 generate -> seed, derivation_path, secret_key, viewing_key, address
+restore seed -> seed, derivation_path, secret_key, viewing_key, address
+generate --shares n --threshold m -> seed shares, derivation_path, viewing_key, address
+combine_shares seed shares -> seed, derivation_path, secret_key, viewing_key, address
 init_db
-init_account viewing_key
+migrate (wallets made before the network was recorded)
+init_account viewing_key...
+add_account viewing_key
 sync
-getbalance -> balance
-prepare_tx recipient_addr amount -> tx_json
-sign secret_key tx_json -> raw_tx_bytes
+getbalance [--all] -> balance
+history [--from date] [--to date] [--csv file] -> received notes and sent transactions
+prepare_tx [recipient_addr amount] [--csv payments.csv] -> tx_json
+review tx_json
+sign [--key-file seed_or_secret_key | --keystore keystore] tx_json -> raw_tx_bytes
+sign [--key-file seed_or_secret_key | --keystore keystore] pszt -> signed pszt
+finalize pszt -> finalized pszt
+extract pszt -> raw_tx_bytes
 submit raw_tx_bytes
+qr_encode file -> qr codes
+qr_decode qr codes -> file
  */
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let mut prog_opt = Opt {
-        lightnode_url: LIGHTNODE_URL.to_string(),
-        unit: ZECUnit::Zec,
-    };
+async fn main() {
     let opts = ZCashColdWallet::parse();
-    let cmd = opts.cmd;
-    if let Some(lightnode_url) = opts.lightwalletd_url {
-        prog_opt.lightnode_url = lightnode_url;
+    let json = opts.json;
+    if let Err(error) = run(opts).await {
+        if json {
+            let code = error
+                .downcast_ref::<WalletError>()
+                .map(WalletError::code)
+                .unwrap_or("error");
+            let message = format!("{:#}", error);
+            println!("{}", serde_json::json!({ "error": { "code": code, "message": message } }));
+        } else {
+            eprintln!("Error: {:?}", error);
+        }
+        std::process::exit(1);
     }
-    prog_opt.unit = opts.unit;
+}
+
+/// Loads the user configuration, then the configuration of the data directory.
+/// Command line options win over the configuration of the data directory,
+/// which wins over the user configuration
+fn load_config(data_dir: Option<PathBuf>) -> Result<(Config, PathBuf)> {
+    let config = Config::load()?;
+    let data_dir = config.data_dir(data_dir);
+    let config = config.with_data_dir(&data_dir)?;
+    Ok((config, data_dir))
+}
+
+async fn run(opts: ZCashColdWallet) -> Result<()> {
+    let json = opts.json;
+    // Generating and restoring keys works without a wallet: a broken configuration
+    // file should not prevent it. The command line options still apply
+    let keys_only = matches!(
+        opts.cmd,
+        Command::Generate { .. } | Command::Restore { .. } | Command::CombineShares { .. }
+    );
+    let (config, data_dir) = match load_config(opts.data_dir.clone()) {
+        Err(error) if keys_only => {
+            eprintln!("Warning: {:#}. Using the default settings", error);
+            let config = Config::default();
+            let data_dir = config.data_dir(opts.data_dir);
+            (config, data_dir)
+        }
+        result => result?,
+    };
+    let network = match (opts.network, config.network.as_ref()) {
+        (Some(network), _) => network,
+        (None, Some(network)) => network.parse()?,
+        (None, None) => DEFAULT_NETWORK,
+    };
+    let unit = match (opts.unit, config.unit.as_ref()) {
+        (Some(unit), _) => unit,
+        (None, Some(unit)) => unit.parse()?,
+        (None, None) => ZECUnit::Zec,
+    };
+    let prog_opt = Opt {
+        lightnode_url: opts
+            .lightwalletd_url
+            .or_else(|| config.lightwalletd_url.clone())
+            .unwrap_or_else(|| network.default_lightnode_url().to_string()),
+        unit,
+        account: AccountId(opts.account),
+        data_dir,
+        data_file: config.data_path.clone().unwrap_or_else(|| PathBuf::from(DATA_PATH)),
+        cache_file: config.cache_path.clone().unwrap_or_else(|| PathBuf::from(CACHE_PATH)),
+        network: config.network_params(network),
+        fee: config.fee.unwrap_or_else(|| u64::from(DEFAULT_FEE)),
+        tls: config.tls.clone().unwrap_or_default(),
+    };
+    let cmd = opts.cmd;
 
     match cmd {
-        Command::Generate { output_filename } => {
-            let keys = generate_key()?;
-            let mut output = create_file(output_filename)?;
-            writeln!(output, "seed phrase: {}", keys.phrase)?;
-            writeln!(output, "spending key: {}", keys.spending_key)?;
-            writeln!(output, "derivation path: {}", keys.derivation_path)?;
-            writeln!(output, "viewing key: {}", keys.viewing_key)?;
-            writeln!(output, "payment address: {}", keys.address)?;
-        },
-        Command::InitDb => init_db()?,
+        Command::Generate {
+            output_filename,
+            passphrase,
+            account_index,
+            keystore,
+            shares,
+            threshold,
+        } => {
+            let shares = match (shares, threshold) {
+                (Some(count), Some(threshold)) => Some((threshold, count)),
+                (None, None) => None,
+                _ => return Err(WalletError::Shares("Pass both --shares and --threshold".to_string()).into()),
+            };
+            let passphrase = if passphrase {
+                prompt_passphrase(true)?
+            } else {
+                String::new()
+            };
+            let keys = generate_key(&prog_opt.network, &passphrase, account_index)?;
+            write_keys(&keys, output_filename, keystore, shares, json)?;
+        }
+        Command::Restore {
+            output_filename,
+            seed_file,
+            passphrase,
+            account_index,
+            keystore,
+        } => {
+            let phrase = match seed_file {
+                Some(seed_file) => read_from_file(Some(seed_file)),
+                None => rpassword::read_password_from_tty(Some("Seed phrase: "))?,
+            };
+            let passphrase = if passphrase {
+                prompt_passphrase(false)?
+            } else {
+                String::new()
+            };
+            let keys = restore_key(&prog_opt.network, &phrase, &passphrase, account_index)?;
+            write_keys(&keys, output_filename, keystore, None, json)?;
+        }
+        Command::CombineShares {
+            shares_file,
+            output_filename,
+            passphrase,
+            account_index,
+            keystore,
+        } => {
+            let shares: Vec<String> = read_from_file(shares_file)
+                .lines()
+                .map(str::to_string)
+                .collect();
+            let phrase = combine_shares(&shares)?;
+            let passphrase = if passphrase {
+                prompt_passphrase(false)?
+            } else {
+                String::new()
+            };
+            let keys = restore_key(&prog_opt.network, &phrase, &passphrase, account_index)?;
+            write_keys(&keys, output_filename, keystore, None, json)?;
+        }
+        Command::InitDb => {
+            init_db(&prog_opt)?;
+            if json {
+                println!("{}", serde_json::json!({ "status": "ok" }));
+            }
+        }
+        Command::Migrate => {
+            migrate_db(&prog_opt)?;
+            print_output(
+                json,
+                &serde_json::json!({ "network": network.to_string() }),
+                &format!("The wallet database is now recorded as a {} wallet", network),
+            )?;
+        }
         Command::InitAccount {
-            viewing_key,
+            viewing_keys,
             birth_day,
         } => {
             let birth_height = if let Some(birth_day) = birth_day {
-                find_height(&prog_opt.lightnode_url, &birth_day).await?
+                find_height(&prog_opt, &birth_day).await?
             } else {
                 u64::MAX
             };
-            init_account(&prog_opt.lightnode_url, viewing_key, birth_height).await?
+            let accounts = viewing_keys.len();
+            init_account(&prog_opt, viewing_keys, birth_height).await?;
+            if json {
+                println!("{}", serde_json::json!({ "accounts": accounts }));
+            }
         },
-        Command::Sync => sync(&prog_opt.lightnode_url).await?,
-        Command::ReIndex => scan()?,
-        Command::GetBalance => get_balance(&prog_opt)?,
+        Command::AddAccount {
+            viewing_key,
+            birth_day,
+        } => {
+            let birth_height = match birth_day {
+                Some(birth_day) => Some(find_height(&prog_opt, &birth_day).await?),
+                None => None,
+            };
+            let account = add_account(&prog_opt, viewing_key, birth_height)?;
+            scan(&prog_opt)?;
+            print_output(
+                json,
+                &serde_json::json!({ "account": account.0 }),
+                &format!("Account {} added", account.0),
+            )?;
+        }
+        Command::Sync => {
+            let result = sync(&prog_opt).await?;
+            print_output(
+                json,
+                &result,
+                &format!(
+                    "Synced to {}. Blocks after {} are not confirmed yet\nScan completed",
+                    result.synced_height, result.confirmed_height
+                ),
+            )?;
+        }
+        Command::ReIndex => {
+            scan(&prog_opt)?;
+            print_output(json, &serde_json::json!({ "status": "ok" }), "Scan completed")?;
+        }
+        Command::GetBalance { all } => {
+            let unit = &prog_opt.unit;
+            let balances = get_balance(&prog_opt, all)?;
+            if all {
+                let all_accounts: Amounts = balances.iter().map(|b| b.amounts).sum();
+                let mut lines: Vec<String> = balances
+                    .iter()
+                    .map(|b| format!("Account {}: {}", b.account, describe_balance(&b.amounts, unit)))
+                    .collect();
+                lines.push(format!("Total: {}", describe_balance(&all_accounts, unit)));
+                let result = serde_json::json!({
+                    "accounts": balances,
+                    "all_accounts": all_accounts,
+                });
+                print_output(json, &result, &lines.join("\n"))?;
+            } else {
+                let balance = &balances[0];
+                let text = format!(
+                    "Balance: {}\n  Spendable: {}\n  Pending: {}\n  Locked in unconfirmed spends: {}",
+                    unit.from_satoshis(balance.amounts.total),
+                    unit.from_satoshis(balance.amounts.spendable),
+                    unit.from_satoshis(balance.amounts.pending),
+                    unit.from_satoshis(balance.amounts.locked),
+                );
+                print_output(json, balance, &text)?;
+            }
+        }
+        Command::History { from, to, csv } => {
+            let unit = &prog_opt.unit;
+            let history = get_history(&prog_opt, from, to)?;
+            if let Some(csv) = csv {
+                write_history_csv(File::create(&csv)?, &history, &prog_opt)?;
+            }
+            let lines: Vec<String> = history
+                .iter()
+                .map(|entry| {
+                    let mut line = format!(
+                        "{:>8} {:19} {:8} {} {} {}",
+                        entry.height.map(|h| h.to_string()).unwrap_or_else(|| "pending".to_string()),
+                        entry.date(),
+                        entry.direction,
+                        entry.txid,
+                        unit.from_satoshis(entry.amount),
+                        unit,
+                    );
+                    if !entry.confirmed {
+                        line.push_str(" (unconfirmed)");
+                    }
+                    if entry.spent == Some(true) {
+                        line.push_str(" (spent)");
+                    }
+                    if let Some(memo) = entry.memo.as_ref() {
+                        line.push_str(&format!(" memo: {}", memo));
+                    }
+                    line
+                })
+                .collect();
+            let text = if lines.is_empty() {
+                "No transactions".to_string()
+            } else {
+                lines.join("\n")
+            };
+            print_output(json, &serde_json::json!({ "history": history }), &text)?;
+        }
         Command::PrepareTx {
             amount,
             recipient_addr,
+            csv,
+            memo,
+            memo_hex,
+            pszt,
+            qr,
             output_filename,
         } => {
+            let mut payments = match csv {
+                Some(csv) => read_payments_csv(&read_from_file(Some(csv)), &prog_opt.unit)?,
+                None => Vec::new(),
+            };
+            let memo = match (memo, memo_hex) {
+                (Some(memo), _) => Some(memo.into_bytes()),
+                (None, Some(memo_hex)) => Some(hex::decode(memo_hex)?),
+                (None, None) => None,
+            };
+            match (recipient_addr, amount) {
+                (Some(addr), Some(amount)) => payments.push(Payment {
+                    addr,
+                    amount: prog_opt.unit.to_satoshis(&amount)?,
+                    memo,
+                }),
+                (None, None) if memo.is_none() => (),
+                _ => return Err(WalletError::NoRecipient.into()),
+            }
             let mut output = create_file(output_filename)?;
-            let tx = prepare_tx(&recipient_addr, amount, &prog_opt.unit)?;
-            let tx_json = serde_json::to_string(&tx)?;
-            writeln!(output, "{}", tx_json)?;
+            let tx = prepare_tx(&payments, &prog_opt)?;
+            let mut data = Vec::new();
+            if pszt {
+                Pszt::new(tx, &get_ovk(&prog_opt)?, network)?.write(&mut data)?;
+            } else {
+                let tx_json = seal(&tx, &get_ovk(&prog_opt)?, network)?;
+                writeln!(data, "{}", tx_json)?;
+            }
+            output.write_all(&data)?;
+            write_qr(&data, qr)?;
         }
         Command::Sign {
-            spending_key,
             tx_json_file,
             output_filename,
+            key_file,
+            keystore,
+            passphrase,
+            account_index,
+            qr,
+        } => {
+            let extsk = match keystore {
+                Some(keystore) => {
+                    // The keystore holds the key of a single account, already derived
+                    if key_file.is_some() || passphrase || account_index.is_some() {
+                        return Err(WalletError::Keystore(
+                            "--key-file, --passphrase and --account-index cannot be used with --keystore".to_string(),
+                        )
+                        .into());
+                    }
+                    let password = prompt_secret("Keystore password", false)?;
+                    let keys = decrypt_keys(&read_from_file(Some(keystore)), &password)?;
+                    spending_key_from_secret(&prog_opt.network, &keys.spending_key, "", 0)?
+                }
+                None => {
+                    // Never take secrets from the command line: they would end up in the shell history
+                    let secret = match key_file {
+                        Some(key_file) => read_from_file(Some(key_file)),
+                        None => rpassword::read_password_from_tty(Some("Seed phrase or spending key: "))?,
+                    };
+                    let passphrase = if passphrase {
+                        prompt_passphrase(false)?
+                    } else {
+                        String::new()
+                    };
+                    spending_key_from_secret(&prog_opt.network, &secret, &passphrase, account_index.unwrap_or(0))?
+                }
+            };
+            let data = read_bytes_from_file(tx_json_file)?;
+            let mut signed = Vec::new();
+            if Pszt::is_pszt(&data) {
+                let mut pszt = Pszt::read(&data[..], Some(&extsk.expsk.ovk), network)?;
+                check_fee(&pszt.tx, &prog_opt)?;
+                eprint!("{}", describe_tx(&pszt.tx, &prog_opt.unit)?);
+                confirm("Sign this transaction?")?;
+                sign_pszt(&extsk, &mut pszt, &prog_opt)?;
+                pszt.write(&mut signed)?;
+            } else {
+                let tx_json = String::from_utf8(data).or(Err(WalletError::TxParse))?;
+                let tx = open(tx_json.trim_end(), Some(&extsk.expsk.ovk), network)?;
+                check_fee(&tx, &prog_opt)?;
+                eprint!("{}", describe_tx(&tx, &prog_opt.unit)?);
+                confirm("Sign this transaction?")?;
+                let raw_tx = sign_tx(&extsk, &tx, &prog_opt)?;
+                writeln!(signed, "{}", hex::encode(&raw_tx.data))?;
+            }
+            // Only now: a declined or rejected transaction must leave an existing output file alone
+            let mut output = create_file(output_filename)?;
+            output.write_all(&signed)?;
+            write_qr(&signed, qr)?;
+        }
+        Command::Review { tx_json_file } => {
+            let data = read_bytes_from_file(tx_json_file)?;
+            let tx = if Pszt::is_pszt(&data) {
+                Pszt::read(&data[..], None, network)?.tx
+            } else {
+                let tx_json = String::from_utf8(data).or(Err(WalletError::TxParse))?;
+                open(tx_json.trim_end(), None, network)?
+            };
+            if json {
+                check_fee(&tx, &prog_opt)?;
+                println!("{}", serde_json::json!({ "transaction": tx, "fee_check": "passed" }));
+            } else {
+                print!("{}", describe_tx(&tx, &prog_opt.unit)?);
+                check_fee(&tx, &prog_opt)?;
+                println!("Fee check passed");
+            }
+        }
+        Command::Finalize {
+            pszt_file,
+            output_filename,
+        } => {
+            let data = read_bytes_from_file(pszt_file)?;
+            let mut pszt = Pszt::read(&data[..], Some(&get_ovk(&prog_opt)?), network)?;
+            finalize_pszt(&mut pszt, &prog_opt.network)?;
+            let mut output = create_file(output_filename)?;
+            pszt.write(&mut output)?;
+        }
+        Command::Extract {
+            pszt_file,
+            output_filename,
         } => {
+            let data = read_bytes_from_file(pszt_file)?;
+            let pszt = Pszt::read(&data[..], None, network)?;
+            let raw_tx = extract_pszt(&pszt)?;
             let mut output = create_file(output_filename)?;
-            let tx_json = read_from_file(tx_json_file);
-            let tx: Tx = serde_json::from_str(&tx_json).or(Err(WalletError::TxParse))?;
-            let raw_tx = sign_tx(&spending_key, &tx, &prog_opt)?;
             writeln!(output, "{}", hex::encode(&raw_tx.data))?;
         }
-        Command::Submit { raw_tx_file } => {
-            let raw_tx = read_from_file(raw_tx_file);
+        Command::Submit { raw_tx_file, qr } => {
+            let raw_tx = if qr.is_empty() {
+                read_from_file(raw_tx_file)
+            } else {
+                let data = decode_parts(&read_png_parts(&qr)?)?;
+                String::from_utf8(data)?.trim_end().to_string()
+            };
             let raw_tx = RawTransaction {
                 data: hex::decode(raw_tx)?,
                 height: 0,
             };
-            submit(raw_tx, &prog_opt.lightnode_url).await?;
+            let txid = submit(raw_tx, &prog_opt).await?;
+            print_output(
+                json,
+                &serde_json::json!({ "txid": txid }),
+                &format!("Success! tx id: {}", txid),
+            )?;
+        }
+        Command::QrEncode {
+            input_file,
+            png_prefix,
+            fragment_size,
+            extra_parts,
+        } => {
+            let data = read_bytes_from_file(input_file)?;
+            let parts = encode_parts(&data, fragment_size, extra_parts);
+            match png_prefix {
+                Some(png_prefix) => {
+                    for file_name in write_png_parts(&parts, &png_prefix)? {
+                        println!("{}", file_name);
+                    }
+                }
+                None => {
+                    // Show the parts one after the other
+                    for (i, part) in parts.iter().enumerate() {
+                        println!("{}", render_terminal(part)?);
+                        eprint!("Part {}/{}. Press Enter for the next one", i + 1, parts.len());
+                        let mut line = String::new();
+                        std::io::stdin().read_line(&mut line)?;
+                    }
+                }
+            }
+        }
+        Command::QrDecode {
+            images,
+            output_filename,
+        } => {
+            let data = decode_parts(&read_png_parts(&images)?)?;
+            let mut output = create_file(output_filename)?;
+            output.write_all(&data)?;
         }
     }
 
@@ -0,0 +1,183 @@
+use crate::WalletError;
+use serde::Deserialize;
+use std::str::FromStr;
+use zcash_primitives::consensus::{BlockHeight, Network, NetworkUpgrade, Parameters};
+use zcash_primitives::constants::{mainnet, testnet};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetworkType {
+    Main,
+    Test,
+    Regtest,
+}
+
+#[cfg(not(feature = "mainnet"))]
+pub const DEFAULT_NETWORK: NetworkType = NetworkType::Test;
+#[cfg(feature = "mainnet")]
+pub const DEFAULT_NETWORK: NetworkType = NetworkType::Main;
+
+impl FromStr for NetworkType {
+    type Err = WalletError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "main" => NetworkType::Main,
+            "test" => NetworkType::Test,
+            "regtest" => NetworkType::Regtest,
+            _ => return Err(WalletError::UnknownNetwork(s.to_string())),
+        })
+    }
+}
+
+impl std::fmt::Display for NetworkType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkType::Main => write!(f, "main"),
+            NetworkType::Test => write!(f, "test"),
+            NetworkType::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+impl NetworkType {
+    pub fn default_lightnode_url(&self) -> &'static str {
+        match self {
+            NetworkType::Main => "https://mainnet.lightwalletd.com:9067",
+            NetworkType::Test => "https://testnet.lightwalletd.com:9067",
+            NetworkType::Regtest => "http://127.0.0.1:9067",
+        }
+    }
+}
+
+/// Consensus parameters of a local regtest network, read from the `[regtest]`
+/// section of the configuration file.
+/// By default every upgrade up to Canopy is active from block 1, like zcashd started with
+/// -nuparams for each of them, and the address encodings are the zcashd regtest ones
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct RegtestParams {
+    pub overwinter: u32,
+    pub sapling: u32,
+    pub blossom: u32,
+    pub heartwood: u32,
+    pub canopy: u32,
+    pub coin_type: u32,
+    pub hrp_sapling_extended_spending_key: String,
+    pub hrp_sapling_extended_full_viewing_key: String,
+    pub hrp_sapling_payment_address: String,
+    pub b58_pubkey_address_prefix: [u8; 2],
+    pub b58_script_address_prefix: [u8; 2],
+}
+
+impl Default for RegtestParams {
+    fn default() -> Self {
+        RegtestParams {
+            overwinter: 1,
+            sapling: 1,
+            blossom: 1,
+            heartwood: 1,
+            canopy: 1,
+            coin_type: testnet::COIN_TYPE,
+            hrp_sapling_extended_spending_key: "secret-extended-key-regtest".to_string(),
+            hrp_sapling_extended_full_viewing_key: "zxviewregtestsapling".to_string(),
+            hrp_sapling_payment_address: "zregtestsapling".to_string(),
+            b58_pubkey_address_prefix: testnet::B58_PUBKEY_ADDRESS_PREFIX,
+            b58_script_address_prefix: testnet::B58_SCRIPT_ADDRESS_PREFIX,
+        }
+    }
+}
+
+/// Network parameters selected at runtime with --network
+/// Main and test delegate to the librustzcash parameters
+#[derive(Debug, Clone)]
+pub struct Params {
+    pub network_type: NetworkType,
+    regtest: RegtestParams,
+}
+
+impl Params {
+    pub fn new(network_type: NetworkType) -> Self {
+        Params {
+            network_type,
+            regtest: RegtestParams::default(),
+        }
+    }
+
+    pub fn regtest(regtest: RegtestParams) -> Self {
+        Params {
+            network_type: NetworkType::Regtest,
+            regtest,
+        }
+    }
+
+    fn network(&self) -> Option<Network> {
+        match self.network_type {
+            NetworkType::Main => Some(Network::MainNetwork),
+            NetworkType::Test => Some(Network::TestNetwork),
+            NetworkType::Regtest => None,
+        }
+    }
+}
+
+impl Parameters for Params {
+    fn activation_height(&self, nu: NetworkUpgrade) -> Option<BlockHeight> {
+        if let Some(network) = self.network() {
+            return network.activation_height(nu);
+        }
+        let height = match nu {
+            NetworkUpgrade::Overwinter => self.regtest.overwinter,
+            NetworkUpgrade::Sapling => self.regtest.sapling,
+            NetworkUpgrade::Blossom => self.regtest.blossom,
+            NetworkUpgrade::Heartwood => self.regtest.heartwood,
+            NetworkUpgrade::Canopy => self.regtest.canopy,
+            #[allow(unreachable_patterns)]
+            _ => return None,
+        };
+        Some(BlockHeight::from_u32(height))
+    }
+
+    fn coin_type(&self) -> u32 {
+        match self.network() {
+            Some(network) => network.coin_type(),
+            None => self.regtest.coin_type,
+        }
+    }
+
+    fn hrp_sapling_extended_spending_key(&self) -> &str {
+        match self.network_type {
+            NetworkType::Main => mainnet::HRP_SAPLING_EXTENDED_SPENDING_KEY,
+            NetworkType::Test => testnet::HRP_SAPLING_EXTENDED_SPENDING_KEY,
+            NetworkType::Regtest => &self.regtest.hrp_sapling_extended_spending_key,
+        }
+    }
+
+    fn hrp_sapling_extended_full_viewing_key(&self) -> &str {
+        match self.network_type {
+            NetworkType::Main => mainnet::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+            NetworkType::Test => testnet::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+            NetworkType::Regtest => &self.regtest.hrp_sapling_extended_full_viewing_key,
+        }
+    }
+
+    fn hrp_sapling_payment_address(&self) -> &str {
+        match self.network_type {
+            NetworkType::Main => mainnet::HRP_SAPLING_PAYMENT_ADDRESS,
+            NetworkType::Test => testnet::HRP_SAPLING_PAYMENT_ADDRESS,
+            NetworkType::Regtest => &self.regtest.hrp_sapling_payment_address,
+        }
+    }
+
+    fn b58_pubkey_address_prefix(&self) -> [u8; 2] {
+        match self.network() {
+            Some(network) => network.b58_pubkey_address_prefix(),
+            None => self.regtest.b58_pubkey_address_prefix,
+        }
+    }
+
+    fn b58_script_address_prefix(&self) -> [u8; 2] {
+        match self.network() {
+            Some(network) => network.b58_script_address_prefix(),
+            None => self.regtest.b58_script_address_prefix,
+        }
+    }
+}
@@ -0,0 +1,609 @@
+use crate::envelope::{checksum, mac};
+use crate::network::{NetworkType, Params};
+use crate::sign::{decode_memo, sign_tx};
+use crate::{grpc::RawTransaction, AddressType, Opt, Result, Tx, TxIn, TxOut, WalletError};
+use std::io::{Read, Write};
+use zcash_client_backend::encoding::{decode_payment_address, decode_transparent_address};
+use zcash_primitives::{
+    consensus::{BlockHeight, Parameters},
+    keys::OutgoingViewingKey,
+    memo::MemoBytes,
+    merkle_tree::{Hashable, IncrementalWitness},
+    note_encryption::try_sapling_output_recovery,
+    sapling::Node,
+    transaction::Transaction,
+    zip32::ExtendedSpendingKey,
+};
+
+/// Magic bytes at the start of every PSZT file, in the spirit of PSBT's `psbt\xff`
+pub const PSZT_MAGIC: &[u8; 5] = b"pszt\xff";
+pub const PSZT_VERSION: u32 = 1;
+const MAX_FIELD_SIZE: usize = 1 << 24;
+const DIGEST_SIZE: usize = 32;
+/// The transaction builder pads the shielded outputs of a transaction that has spends
+/// to this number with dummy outputs
+const MIN_SHIELDED_OUTPUTS: usize = 2;
+
+/// Partially Signed ZCash Transaction
+///
+/// A binary container that carries everything a signer needs: the anchor,
+/// the note plaintexts and witnesses of the inputs, the outputs and the change.
+/// It goes through the following steps:
+/// - prepare: the online computer creates it from the wallet notes
+/// - sign: the offline computer adds the signed transaction
+/// - finalize: the signed transaction is checked against the PSZT content
+/// - extract: the raw transaction is taken out for submission
+///
+/// Like the json transaction file, the PSZT ends with a checksum of its content
+/// and carries a MAC of the transaction request keyed with the outgoing viewing key
+pub struct Pszt {
+    pub network: String,
+    pub anchor: [u8; 32],
+    pub tx: Tx,
+    mac: Option<Vec<u8>>,
+    pub signed_tx: Option<Vec<u8>>,
+    pub finalized: bool,
+}
+
+impl Pszt {
+    pub fn new(tx: Tx, ovk: &OutgoingViewingKey, network: NetworkType) -> Result<Pszt> {
+        let mut pszt = Pszt {
+            network: network.to_string(),
+            anchor: witness_anchor(&tx.inputs)?,
+            tx,
+            mac: None,
+            signed_tx: None,
+            finalized: false,
+        };
+        let request = pszt.request()?;
+        pszt.mac = Some(mac(ovk, PSZT_VERSION, &pszt.network, &request).as_bytes().to_vec());
+        Ok(pszt)
+    }
+
+    pub fn is_pszt(data: &[u8]) -> bool {
+        data.starts_with(PSZT_MAGIC)
+    }
+
+    /// Serializes the part made by prepare: the anchor and the transaction.
+    /// This is what the MAC covers
+    fn request(&self) -> Result<Vec<u8>> {
+        let mut w = Vec::new();
+        w.write_all(&self.anchor)?;
+        w.write_all(&self.tx.height.to_le_bytes())?;
+        w.write_all(&self.tx.fee.to_le_bytes())?;
+        w.write_all(&(self.tx.inputs.len() as u32).to_le_bytes())?;
+        for input in self.tx.inputs.iter() {
+            write_bytes(&mut w, &hex::decode(&input.diversifier)?)?;
+            write_bytes(&mut w, input.addr.as_bytes())?;
+            w.write_all(&input.amount.to_le_bytes())?;
+            w.write_all(&[input.z212 as u8])?;
+            write_bytes(&mut w, &hex::decode(&input.rseed)?)?;
+            write_bytes(&mut w, &hex::decode(&input.witness)?)?;
+        }
+        w.write_all(&(self.tx.outputs.len() as u32).to_le_bytes())?;
+        for output in self.tx.outputs.iter() {
+            write_output(&mut w, output)?;
+        }
+        match self.tx.change.as_ref() {
+            Some(change) => {
+                w.write_all(&[1])?;
+                write_output(&mut w, change)?;
+            }
+            None => w.write_all(&[0])?,
+        }
+        Ok(w)
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        let mut data = Vec::new();
+        data.write_all(PSZT_MAGIC)?;
+        data.write_all(&PSZT_VERSION.to_le_bytes())?;
+        write_bytes(&mut data, self.network.as_bytes())?;
+        data.write_all(&self.request()?)?;
+        match self.mac.as_ref() {
+            Some(mac) => {
+                data.write_all(&[1])?;
+                write_bytes(&mut data, mac)?;
+            }
+            None => data.write_all(&[0])?,
+        }
+        match self.signed_tx.as_ref() {
+            Some(signed_tx) => {
+                data.write_all(&[1])?;
+                write_bytes(&mut data, signed_tx)?;
+            }
+            None => data.write_all(&[0])?,
+        }
+        data.write_all(&[self.finalized as u8])?;
+        let checksum = checksum(PSZT_VERSION, &self.network, &data);
+        w.write_all(&data)?;
+        w.write_all(checksum.as_bytes())?;
+        Ok(())
+    }
+
+    /// Parses a PSZT and checks its checksum and that it was made for the given network.
+    ///
+    /// The MAC is verified when the caller has the outgoing viewing key
+    pub fn read<R: Read>(
+        mut r: R,
+        ovk: Option<&OutgoingViewingKey>,
+        expected_network: NetworkType,
+    ) -> Result<Pszt> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        if !Pszt::is_pszt(&data) {
+            return Err(WalletError::Pszt("Not a PSZT file".to_string()).into());
+        }
+        if data.len() < PSZT_MAGIC.len() + DIGEST_SIZE {
+            return Err(WalletError::Checksum.into());
+        }
+        let (data, stored_checksum) = data.split_at(data.len() - DIGEST_SIZE);
+        let mut r = &data[PSZT_MAGIC.len()..];
+
+        let version = read_u32(&mut r)?;
+        if version != PSZT_VERSION {
+            return Err(WalletError::TxVersion(version, PSZT_VERSION).into());
+        }
+        let network = read_string(&mut r)?;
+        if checksum(version, &network, data).as_bytes() != stored_checksum {
+            return Err(WalletError::Checksum.into());
+        }
+        if network != expected_network.to_string() {
+            return Err(WalletError::WrongNetwork(network, expected_network.to_string()).into());
+        }
+
+        let request_start = data.len() - r.len();
+        let mut anchor = [0u8; 32];
+        r.read_exact(&mut anchor)?;
+        let height = read_u64(&mut r)? as i64;
+        let fee = read_u64(&mut r)?;
+        let input_count = read_u32(&mut r)?;
+        let mut inputs = Vec::new();
+        for _ in 0..input_count {
+            inputs.push(TxIn {
+                diversifier: hex::encode(read_bytes(&mut r)?),
+                addr: read_string(&mut r)?,
+                amount: read_u64(&mut r)?,
+                z212: read_u8(&mut r)? != 0,
+                rseed: hex::encode(read_bytes(&mut r)?),
+                witness: hex::encode(read_bytes(&mut r)?),
+            });
+        }
+        let output_count = read_u32(&mut r)?;
+        let mut outputs = Vec::new();
+        for _ in 0..output_count {
+            outputs.push(read_output(&mut r)?);
+        }
+        let change = match read_u8(&mut r)? {
+            0 => None,
+            _ => Some(read_output(&mut r)?),
+        };
+        let request = &data[request_start..data.len() - r.len()];
+
+        let stored_mac = match read_u8(&mut r)? {
+            0 => None,
+            _ => Some(read_bytes(&mut r)?),
+        };
+        if let Some(ovk) = ovk {
+            let expected = mac(ovk, version, &network, request);
+            if stored_mac.as_deref() != Some(expected.as_bytes()) {
+                return Err(WalletError::Mac.into());
+            }
+        }
+        let signed_tx = match read_u8(&mut r)? {
+            0 => None,
+            _ => Some(read_bytes(&mut r)?),
+        };
+        let finalized = read_u8(&mut r)? != 0;
+        if !r.is_empty() {
+            return Err(WalletError::Pszt("Unexpected data at the end".to_string()).into());
+        }
+        Ok(Pszt {
+            network,
+            anchor,
+            tx: Tx {
+                height,
+                inputs,
+                outputs,
+                change,
+                fee,
+            },
+            mac: stored_mac,
+            signed_tx,
+            finalized,
+        })
+    }
+}
+
+/// Root of the note commitment tree that the witnesses of the inputs lead to.
+/// They must all have the same
+fn witness_anchor(inputs: &[TxIn]) -> Result<[u8; 32]> {
+    let mut anchor = [0u8; 32];
+    for (i, input) in inputs.iter().enumerate() {
+        let w = hex::decode(&input.witness)?;
+        let witness = IncrementalWitness::<Node>::read(&w[..])?;
+        let mut root = Vec::new();
+        witness.root().write(&mut root)?;
+        if i == 0 {
+            anchor.copy_from_slice(&root);
+        } else if anchor[..] != root[..] {
+            return Err(WalletError::Pszt("Inputs have different anchors".to_string()).into());
+        }
+    }
+    Ok(anchor)
+}
+
+pub fn sign_pszt(extsk: &ExtendedSpendingKey, pszt: &mut Pszt, opts: &Opt) -> Result<()> {
+    let raw_tx = sign_tx(extsk, &pszt.tx, opts)?;
+    pszt.signed_tx = Some(raw_tx.data);
+    pszt.finalized = false;
+    Ok(())
+}
+
+/// Checks that the signed transaction is the one the PSZT describes:
+/// - it spends as many notes as the PSZT has inputs, at the PSZT anchor
+/// - every output and the change pay the expected amount to the expected recipient,
+/// with the expected memo. Shielded outputs are decrypted with their outgoing viewing key
+/// - it pays the expected fee
+pub fn finalize_pszt(pszt: &mut Pszt, network: &Params) -> Result<()> {
+    let signed_tx = pszt
+        .signed_tx
+        .as_ref()
+        .ok_or_else(|| WalletError::Pszt("Transaction is not signed".to_string()))?;
+    let tx = Transaction::read(&signed_tx[..])?;
+    let mismatch = |what: &str| {
+        WalletError::Pszt(format!("Signed transaction does not match the PSZT: {}", what))
+    };
+
+    if witness_anchor(&pszt.tx.inputs)? != pszt.anchor {
+        return Err(mismatch("the witnesses do not lead to the anchor").into());
+    }
+    if tx.shielded_spends.len() != pszt.tx.inputs.len()
+        || tx
+            .shielded_spends
+            .iter()
+            .any(|spend| spend.anchor.to_bytes() != pszt.anchor)
+    {
+        return Err(mismatch("spends").into());
+    }
+
+    let height = BlockHeight::from_u32(pszt.tx.height as u32);
+    let expected_shielded: Vec<&TxOut> = pszt
+        .tx
+        .outputs
+        .iter()
+        .chain(pszt.tx.change.iter())
+        .filter(|o| o.addr_type == AddressType::Shielded)
+        .collect();
+    // The builder shuffles the outputs
+    let mut unmatched: Vec<_> = tx.shielded_outputs.iter().collect();
+    for output in expected_shielded.iter() {
+        let mut ovk = [0u8; 32];
+        hex::decode_to_slice(&output.ovk, &mut ovk)?;
+        let ovk = OutgoingViewingKey(ovk);
+        let addr = decode_payment_address(network.hrp_sapling_payment_address(), &output.addr)?
+            .ok_or_else(|| WalletError::Decode(output.addr.clone()))?;
+        let memo = match output.memo.as_ref() {
+            Some(memo) => MemoBytes::from(decode_memo(memo)?),
+            None => MemoBytes::empty(),
+        };
+        let position = unmatched
+            .iter()
+            .position(|od| match try_sapling_output_recovery(network, height, &ovk, od) {
+                Some((note, to, m)) => {
+                    note.value == output.amount && to == addr && m.as_slice() == memo.as_slice()
+                }
+                None => false,
+            })
+            .ok_or_else(|| mismatch(&format!("output to {}", output.addr)))?;
+        unmatched.remove(position);
+    }
+    // Only the dummy outputs of the builder, which nobody can decrypt, may be left
+    let padding = if pszt.tx.inputs.is_empty() {
+        0
+    } else {
+        MIN_SHIELDED_OUTPUTS.saturating_sub(expected_shielded.len())
+    };
+    if unmatched.len() != padding {
+        return Err(mismatch("unexpected shielded outputs").into());
+    }
+
+    let mut vout: Vec<_> = tx.vout.iter().collect();
+    for output in pszt
+        .tx
+        .outputs
+        .iter()
+        .filter(|o| o.addr_type == AddressType::Transparent)
+    {
+        let addr = decode_transparent_address(
+            &network.b58_pubkey_address_prefix(),
+            &network.b58_script_address_prefix(),
+            &output.addr,
+        )?
+        .ok_or_else(|| WalletError::Decode(output.addr.clone()))?;
+        let script = addr.script();
+        let position = vout
+            .iter()
+            .position(|o| u64::from(o.value) == output.amount && o.script_pubkey.0 == script.0)
+            .ok_or_else(|| mismatch(&format!("output to {}", output.addr)))?;
+        vout.remove(position);
+    }
+    if !vout.is_empty() || !tx.vin.is_empty() {
+        return Err(mismatch("unexpected transparent inputs or outputs").into());
+    }
+
+    let transparent: i64 = tx.vout.iter().map(|o| i64::from(o.value)).sum();
+    if i64::from(tx.value_balance) - transparent != pszt.tx.fee as i64 {
+        return Err(mismatch("fee").into());
+    }
+
+    pszt.finalized = true;
+    Ok(())
+}
+
+pub fn extract_pszt(pszt: &Pszt) -> Result<RawTransaction> {
+    match (pszt.finalized, pszt.signed_tx.as_ref()) {
+        (true, Some(signed_tx)) => Ok(RawTransaction {
+            data: signed_tx.clone(),
+            height: 0,
+        }),
+        _ => Err(WalletError::Pszt("Transaction is not finalized".to_string()).into()),
+    }
+}
+
+fn write_output<W: Write>(w: &mut W, output: &TxOut) -> Result<()> {
+    let addr_type = match output.addr_type {
+        AddressType::Shielded => 0u8,
+        AddressType::Transparent => 1u8,
+    };
+    w.write_all(&[addr_type])?;
+    write_bytes(w, output.addr.as_bytes())?;
+    w.write_all(&output.amount.to_le_bytes())?;
+    write_bytes(w, &hex::decode(&output.ovk)?)?;
+    match output.memo.as_ref() {
+        Some(memo) => {
+            w.write_all(&[1])?;
+            write_bytes(w, &hex::decode(memo)?)?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    Ok(())
+}
+
+fn read_output<R: Read>(r: &mut R) -> Result<TxOut> {
+    let addr_type = match read_u8(r)? {
+        0 => AddressType::Shielded,
+        1 => AddressType::Transparent,
+        t => return Err(WalletError::Pszt(format!("Unknown address type {}", t)).into()),
+    };
+    let addr = read_string(r)?;
+    let amount = read_u64(r)?;
+    let ovk = hex::encode(read_bytes(r)?);
+    let memo = match read_u8(r)? {
+        0 => None,
+        _ => Some(hex::encode(read_bytes(r)?)),
+    };
+    Ok(TxOut {
+        addr_type,
+        addr,
+        amount,
+        ovk,
+        memo,
+    })
+}
+
+fn write_bytes<W: Write>(w: &mut W, data: &[u8]) -> Result<()> {
+    w.write_all(&(data.len() as u32).to_le_bytes())?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let len = read_u32(r)? as usize;
+    if len > MAX_FIELD_SIZE {
+        return Err(WalletError::Pszt("Field too large".to_string()).into());
+    }
+    let mut data = vec![0u8; len];
+    r.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn read_string<R: Read>(r: &mut R) -> Result<String> {
+    let s = String::from_utf8(read_bytes(r)?)
+        .map_err(|_| WalletError::Pszt("Invalid string".to_string()))?;
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OVK: OutgoingViewingKey = OutgoingViewingKey([7u8; 32]);
+
+    /// Without inputs, so that no witness is needed
+    fn test_tx() -> Tx {
+        Tx {
+            height: 1_200_000,
+            inputs: Vec::new(),
+            outputs: vec![
+                TxOut {
+                    addr_type: AddressType::Shielded,
+                    addr: "ztestsapling1recipient".to_string(),
+                    amount: 50_000,
+                    ovk: hex::encode([1u8; 32]),
+                    memo: Some(hex::encode("Invoice 42")),
+                },
+                TxOut {
+                    addr_type: AddressType::Transparent,
+                    addr: "tmRecipient".to_string(),
+                    amount: 20_000,
+                    ovk: hex::encode([1u8; 32]),
+                    memo: None,
+                },
+            ],
+            change: Some(TxOut {
+                addr_type: AddressType::Shielded,
+                addr: "ztestsapling1change".to_string(),
+                amount: 9_000,
+                ovk: hex::encode([1u8; 32]),
+                memo: None,
+            }),
+            fee: 1_000,
+        }
+    }
+
+    fn test_pszt() -> Vec<u8> {
+        let mut data = Vec::new();
+        Pszt::new(test_tx(), &OVK, NetworkType::Test)
+            .unwrap()
+            .write(&mut data)
+            .unwrap();
+        data
+    }
+
+    fn error(data: &[u8], ovk: Option<&OutgoingViewingKey>) -> WalletError {
+        match Pszt::read(data, ovk, NetworkType::Test) {
+            Ok(_) => panic!("the PSZT should be rejected"),
+            Err(error) => error.downcast::<WalletError>().unwrap(),
+        }
+    }
+
+    /// Replaces the checksum, as a forger who knows the format would
+    fn with_checksum(content: &[u8]) -> Vec<u8> {
+        let mut data = content.to_vec();
+        data.extend_from_slice(checksum(PSZT_VERSION, "test", content).as_bytes());
+        data
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let data = test_pszt();
+        assert!(Pszt::is_pszt(&data));
+        let pszt = Pszt::read(&data[..], Some(&OVK), NetworkType::Test).unwrap();
+        assert_eq!(
+            serde_json::to_string(&pszt.tx).unwrap(),
+            serde_json::to_string(&test_tx()).unwrap()
+        );
+        assert_eq!(pszt.network, "test");
+        assert_eq!(pszt.anchor, [0u8; 32]);
+        assert!(pszt.signed_tx.is_none());
+        assert!(!pszt.finalized);
+        // Reviewing on the online computer does not need the key
+        assert!(Pszt::read(&data[..], None, NetworkType::Test).is_ok());
+
+        let mut rewritten = Vec::new();
+        pszt.write(&mut rewritten).unwrap();
+        assert_eq!(rewritten, data);
+    }
+
+    #[test]
+    fn read_rejects_wrong_or_missing_mac() {
+        let data = test_pszt();
+        let other_ovk = OutgoingViewingKey([8u8; 32]);
+        assert!(matches!(error(&data, Some(&other_ovk)), WalletError::Mac));
+
+        let mut pszt = Pszt::read(&data[..], None, NetworkType::Test).unwrap();
+        pszt.mac = None;
+        let mut stripped = Vec::new();
+        pszt.write(&mut stripped).unwrap();
+        assert!(matches!(error(&stripped, Some(&OVK)), WalletError::Mac));
+    }
+
+    #[test]
+    fn read_rejects_modified_request() {
+        let mut pszt = Pszt::read(&test_pszt()[..], None, NetworkType::Test).unwrap();
+        pszt.tx.outputs[0].amount += 1;
+        let mut forged = Vec::new();
+        pszt.write(&mut forged).unwrap();
+        // The checksum is recomputed on write, but the MAC does not match anymore
+        assert!(matches!(error(&forged, Some(&OVK)), WalletError::Mac));
+    }
+
+    #[test]
+    fn read_rejects_flipped_byte() {
+        let data = test_pszt();
+        for i in PSZT_MAGIC.len()..data.len() {
+            let mut corrupted = data.clone();
+            corrupted[i] ^= 0x01;
+            assert!(Pszt::read(&corrupted[..], Some(&OVK), NetworkType::Test).is_err());
+        }
+        let mut corrupted = data;
+        let i = corrupted.len() / 2;
+        corrupted[i] ^= 0x01;
+        assert!(matches!(error(&corrupted, Some(&OVK)), WalletError::Checksum));
+    }
+
+    #[test]
+    fn read_rejects_wrong_network() {
+        let data = test_pszt();
+        match Pszt::read(&data[..], Some(&OVK), NetworkType::Main) {
+            Err(error) => assert!(matches!(
+                error.downcast::<WalletError>().unwrap(),
+                WalletError::WrongNetwork(..)
+            )),
+            Ok(_) => panic!("the PSZT should be rejected"),
+        }
+    }
+
+    #[test]
+    fn read_rejects_truncated_file() {
+        let data = test_pszt();
+        assert!(matches!(error(&data[..data.len() - 1], None), WalletError::Checksum));
+        assert!(matches!(error(&data[..PSZT_MAGIC.len() + 4], None), WalletError::Checksum));
+        assert!(matches!(error(b"not a pszt", None), WalletError::Pszt(_)));
+
+        // Content cut short behind a valid checksum fails to parse
+        let content = &data[..data.len() - DIGEST_SIZE];
+        let truncated = with_checksum(&content[..content.len() - 10]);
+        assert!(Pszt::read(&truncated[..], None, NetworkType::Test).is_err());
+    }
+
+    #[test]
+    fn read_rejects_trailing_data() {
+        let mut data = test_pszt();
+        data.push(0);
+        assert!(matches!(error(&data, None), WalletError::Checksum));
+
+        let data = test_pszt();
+        let mut content = data[..data.len() - DIGEST_SIZE].to_vec();
+        content.push(0);
+        let padded = with_checksum(&content);
+        assert!(matches!(error(&padded, None), WalletError::Pszt(_)));
+    }
+
+    #[test]
+    fn extract_needs_a_finalized_pszt() {
+        let mut pszt = Pszt::new(test_tx(), &OVK, NetworkType::Test).unwrap();
+        assert!(extract_pszt(&pszt).is_err());
+        pszt.signed_tx = Some(vec![1, 2, 3]);
+        assert!(extract_pszt(&pszt).is_err());
+        pszt.finalized = true;
+        assert_eq!(extract_pszt(&pszt).unwrap().data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn finalize_needs_a_signed_pszt() {
+        let mut pszt = Pszt::new(test_tx(), &OVK, NetworkType::Test).unwrap();
+        let network = Params::new(NetworkType::Test);
+        assert!(finalize_pszt(&mut pszt, &network).is_err());
+        assert!(!pszt.finalized);
+    }
+}
@@ -0,0 +1,338 @@
+use crate::{Result, WalletError};
+use blake2b_simd::Params;
+use image::Luma;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::{EcLevel, QrCode};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Default number of payload bytes per QR code
+pub const DEFAULT_FRAGMENT_SIZE: usize = 200;
+
+const UR_TYPE: &str = "UR:ZCASH-COLD";
+
+/// Upper bound on the number of fragments of a payload, i.e. 200 KB at the default size.
+/// The header of a part is not authenticated, so it must not drive the decoder into
+/// an endless loop
+const MAX_FRAGMENTS: usize = 1000;
+
+/// Upper bound on the sequence number of a part, as a multiple of the number of fragments
+const MAX_SEQ_FACTOR: usize = 100;
+
+/// Splits data into multi-part QR payloads, in the spirit of BC-UR.
+///
+/// The first `total` parts carry the fragments of the data as they are.
+/// Every extra part is a fountain code: the XOR of a pseudo-random set of fragments
+/// chosen from its sequence number. The receiver can rebuild the data from any
+/// large enough subset of the parts, so missed frames do not matter.
+///
+/// Each part reads `UR:ZCASH-COLD/<seq>-<total>/<length>-<checksum>/<hex fragment>`
+/// and only uses characters of the QR alphanumeric mode.
+pub fn encode_parts(data: &[u8], fragment_size: usize, extra_parts: usize) -> Vec<String> {
+    let fragment_size = fragment_size.max(1);
+    let total = ((data.len() + fragment_size - 1) / fragment_size).max(1);
+    let mut padded = data.to_vec();
+    padded.resize(total * fragment_size, 0);
+    let fragments: Vec<&[u8]> = padded.chunks(fragment_size).collect();
+    let checksum = checksum(data);
+
+    (1..=total + extra_parts)
+        .map(|seq| {
+            let mut part = vec![0u8; fragment_size];
+            for i in choose_fragments(seq, total, &checksum) {
+                xor_into(&mut part, fragments[i]);
+            }
+            format!(
+                "{}/{}-{}/{}-{}/{}",
+                UR_TYPE,
+                seq,
+                total,
+                data.len(),
+                hex::encode_upper(checksum),
+                hex::encode_upper(part)
+            )
+        })
+        .collect()
+}
+
+/// Encodes with the default fragment size and about 50% of extra fountain parts
+pub fn encode(data: &[u8]) -> Vec<String> {
+    let total = (data.len() + DEFAULT_FRAGMENT_SIZE - 1) / DEFAULT_FRAGMENT_SIZE;
+    encode_parts(data, DEFAULT_FRAGMENT_SIZE, total / 2 + 1)
+}
+
+struct Part {
+    seq: usize,
+    total: usize,
+    length: usize,
+    checksum: [u8; 4],
+    fragment: Vec<u8>,
+}
+
+fn parse_part(s: &str) -> Result<Part> {
+    let invalid = || WalletError::Qr(format!("Not a transaction QR code: {}", s));
+    let s = s.trim().to_uppercase();
+    let rest = s
+        .strip_prefix(UR_TYPE)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .ok_or_else(invalid)?;
+    let fields: Vec<&str> = rest.split('/').collect();
+    if fields.len() != 3 {
+        return Err(invalid().into());
+    }
+    let (seq, total) = split_pair(fields[0]).ok_or_else(invalid)?;
+    let (length, checksum) = split_dash(fields[1]).ok_or_else(invalid)?;
+    let mut cs = [0u8; 4];
+    hex::decode_to_slice(checksum, &mut cs).map_err(|_| invalid())?;
+    Ok(Part {
+        seq,
+        total,
+        length: length.parse().map_err(|_| invalid())?,
+        checksum: cs,
+        fragment: hex::decode(fields[2]).map_err(|_| invalid())?,
+    })
+}
+
+fn split_dash(s: &str) -> Option<(&str, &str)> {
+    let mut it = s.splitn(2, '-');
+    Some((it.next()?, it.next()?))
+}
+
+fn split_pair(s: &str) -> Option<(usize, usize)> {
+    let (a, b) = split_dash(s)?;
+    Some((a.parse().ok()?, b.parse().ok()?))
+}
+
+/// Reassembles the data from QR payloads given in any order.
+/// Duplicates and extra fountain parts are fine
+pub fn decode_parts(parts: &[String]) -> Result<Vec<u8>> {
+    let parts = parts
+        .iter()
+        .map(|p| parse_part(p))
+        .collect::<Result<Vec<_>>>()?;
+    let first = parts
+        .first()
+        .ok_or_else(|| WalletError::Qr("No QR code found".to_string()))?;
+    let (total, length, checksum) = (first.total, first.length, first.checksum);
+    let fragment_size = first.fragment.len();
+    if total == 0 || fragment_size == 0 {
+        return Err(WalletError::Qr("Empty QR code".to_string()).into());
+    }
+    if total > MAX_FRAGMENTS || length > total * fragment_size {
+        return Err(WalletError::Qr("Invalid QR data length".to_string()).into());
+    }
+
+    // Peeling decoder: reduce every mixed part by the fragments we know
+    // until it covers a single unknown fragment
+    let mut known: HashMap<usize, Vec<u8>> = HashMap::new();
+    let mut mixed: Vec<(Vec<usize>, Vec<u8>)> = Vec::new();
+    for part in parts {
+        if part.total != total
+            || part.length != length
+            || part.checksum != checksum
+            || part.fragment.len() != fragment_size
+            || part.seq == 0
+        {
+            return Err(
+                WalletError::Qr("QR codes belong to different transactions".to_string()).into(),
+            );
+        }
+        if part.seq > total * MAX_SEQ_FACTOR {
+            return Err(WalletError::Qr(format!("Invalid QR code number {}", part.seq)).into());
+        }
+        mixed.push((choose_fragments(part.seq, total, &checksum), part.fragment));
+    }
+    let mut progress = true;
+    while progress && known.len() < total {
+        progress = false;
+        for (indexes, fragment) in mixed.iter_mut() {
+            indexes.retain(|i| match known.get(i) {
+                Some(k) => {
+                    xor_into(fragment, k);
+                    false
+                }
+                None => true,
+            });
+            if indexes.len() == 1 {
+                known.insert(indexes[0], fragment.clone());
+                indexes.clear();
+                progress = true;
+            }
+        }
+        mixed.retain(|(indexes, _)| !indexes.is_empty());
+    }
+    if known.len() < total {
+        return Err(WalletError::Qr(format!(
+            "Missing QR codes: got {} of {} fragments",
+            known.len(),
+            total
+        ))
+        .into());
+    }
+
+    let mut data = Vec::with_capacity(total * fragment_size);
+    for i in 0..total {
+        data.extend_from_slice(&known[&i]);
+    }
+    data.truncate(length);
+    if self::checksum(&data) != checksum {
+        return Err(WalletError::Qr("QR data checksum mismatch".to_string()).into());
+    }
+    Ok(data)
+}
+
+/// Writes one PNG file per part: `<prefix>-001.png`, `<prefix>-002.png`, ...
+pub fn write_png_parts(parts: &[String], prefix: &str) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        let code = QrCode::with_error_correction_level(part.as_bytes(), EcLevel::L)?;
+        let image = code.render::<Luma<u8>>().min_dimensions(400, 400).build();
+        let file_name = format!("{}-{:03}.png", prefix, i + 1);
+        image.save(&file_name)?;
+        files.push(file_name);
+    }
+    Ok(files)
+}
+
+pub fn render_terminal(part: &str) -> Result<String> {
+    let code = QrCode::with_error_correction_level(part.as_bytes(), EcLevel::L)?;
+    Ok(code.render::<Dense1x2>().build())
+}
+
+/// Reads every QR code found in the image files
+pub fn read_png_parts<P: AsRef<Path>>(files: &[P]) -> Result<Vec<String>> {
+    let mut parts = Vec::new();
+    for file in files.iter() {
+        let image = image::open(file)?.to_luma8();
+        let mut image = rqrr::PreparedImage::prepare(image);
+        for grid in image.detect_grids() {
+            let (_, content) = grid
+                .decode()
+                .map_err(|e| WalletError::Qr(format!("{:?}", e)))?;
+            parts.push(content);
+        }
+    }
+    Ok(parts)
+}
+
+fn checksum(data: &[u8]) -> [u8; 4] {
+    let hash = Params::new().hash_length(4).hash(data);
+    let mut cs = [0u8; 4];
+    cs.copy_from_slice(hash.as_bytes());
+    cs
+}
+
+/// Fragments mixed into the part with the given sequence number (1-based).
+/// Parts up to `total` carry a single fragment
+fn choose_fragments(seq: usize, total: usize, checksum: &[u8; 4]) -> Vec<usize> {
+    if seq <= total {
+        return vec![seq - 1];
+    }
+    let seed = Params::new()
+        .hash_length(64)
+        .to_state()
+        .update(checksum)
+        .update(&(seq as u64).to_le_bytes())
+        .finalize();
+    let mut bits = seed
+        .as_bytes()
+        .iter()
+        .cycle()
+        .flat_map(|b| (0..8).map(move |i| (b >> i) & 1 == 1));
+    let mut indexes: Vec<usize> = (0..total).filter(|_| bits.next().unwrap()).collect();
+    if indexes.is_empty() {
+        indexes.push(seq % total);
+    }
+    indexes
+}
+
+fn xor_into(a: &mut [u8], b: &[u8]) {
+    for (x, y) in a.iter_mut().zip(b.iter()) {
+        *x ^= *y;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data() -> Vec<u8> {
+        (0..1000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = test_data();
+        let parts = encode(&data);
+        assert_eq!(decode_parts(&parts).unwrap(), data);
+        // Not a multiple of the fragment size, and empty
+        assert_eq!(decode_parts(&encode(&data[..333])).unwrap(), &data[..333]);
+        assert_eq!(decode_parts(&encode(&[])).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn out_of_order_and_duplicate_parts() {
+        let data = test_data();
+        let mut parts = encode_parts(&data, 100, 5);
+        parts.reverse();
+        parts.push(parts[3].clone());
+        parts.push(parts[0].clone());
+        assert_eq!(decode_parts(&parts).unwrap(), data);
+        // Scanners may return the payload in lower case
+        let lower: Vec<String> = parts.iter().map(|p| p.to_lowercase()).collect();
+        assert_eq!(decode_parts(&lower).unwrap(), data);
+    }
+
+    #[test]
+    fn missing_parts_recovered_from_fountain_parts() {
+        let data = test_data();
+        let mut parts = encode_parts(&data, 100, 20);
+        parts.remove(7);
+        parts.remove(2);
+        assert_eq!(decode_parts(&parts).unwrap(), data);
+    }
+
+    #[test]
+    fn missing_parts_fail() {
+        let data = test_data();
+        let mut parts = encode_parts(&data, 100, 0);
+        parts.remove(4);
+        assert!(decode_parts(&parts).is_err());
+        assert!(decode_parts(&[]).is_err());
+    }
+
+    #[test]
+    fn parts_of_different_data_fail() {
+        let data = test_data();
+        let mut parts = encode_parts(&data, 100, 0);
+        let other = encode_parts(&data[1..], 100, 0);
+        parts[5] = other[5].clone();
+        assert!(decode_parts(&parts).is_err());
+    }
+
+    #[test]
+    fn corrupted_part_fails() {
+        let data = test_data();
+        let mut parts = encode_parts(&data, 100, 0);
+        // Flip a byte of the fragment: the data checksum no longer matches
+        let last = parts[0].len() - 1;
+        let flipped = if parts[0].ends_with('0') { "1" } else { "0" };
+        parts[0].replace_range(last.., flipped);
+        assert!(decode_parts(&parts).is_err());
+        assert!(decode_parts(&["UR:ZCASH-COLD/1-1/garbage".to_string()]).is_err());
+    }
+
+    #[test]
+    fn implausible_headers_fail() {
+        let fragment = "00".repeat(10);
+        let part = |header: &str| format!("UR:ZCASH-COLD/{}-01020304/{}", header, fragment);
+        // Too many fragments, even with a single part
+        let err = decode_parts(&[part("1-18446744073709551615/10")]).unwrap_err();
+        assert!(err.to_string().contains("Invalid QR data length"));
+        // More data than the fragments can hold
+        assert!(decode_parts(&[part("1-2/21")]).is_err());
+        // Fountain part far beyond the number of fragments
+        let err = decode_parts(&[part("1-2/20"), part("1000000-2/20")]).unwrap_err();
+        assert!(err.to_string().contains("Invalid QR code number 1000000"));
+    }
+}
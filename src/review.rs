@@ -0,0 +1,103 @@
+use crate::sign::decode_memo;
+use crate::{AddressType, Opt, Result, Tx, TxOut, WalletError, ZECUnit};
+use anyhow::Context;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use zcash_primitives::memo::Memo;
+use zcash_primitives::transaction::components::amount::DEFAULT_FEE;
+
+/// The transaction builder always pays the standard fee, whatever the transaction says.
+/// Any other fee would make the signed transaction differ from the reviewed one
+pub fn check_supported_fee(fee: u64) -> Result<()> {
+    let default_fee = u64::from(DEFAULT_FEE);
+    if fee != default_fee {
+        return Err(WalletError::UnsupportedFee(fee, default_fee).into());
+    }
+    Ok(())
+}
+
+/// Checks that the inputs pay exactly for the outputs, the change and the configured fee.
+/// Anything left over would otherwise silently go to the miners
+pub fn check_fee(tx: &Tx, opts: &Opt) -> Result<()> {
+    let unit = &opts.unit;
+    check_supported_fee(tx.fee)?;
+    let inputs: u64 = tx.inputs.iter().map(|i| i.amount).sum();
+    let outputs: u64 = tx
+        .outputs
+        .iter()
+        .chain(tx.change.iter())
+        .map(|o| o.amount)
+        .sum();
+    if inputs != outputs + tx.fee {
+        return Err(WalletError::Unbalanced(inputs, outputs + tx.fee, unit.clone()).into());
+    }
+    let expected_fee = opts.fee;
+    if tx.fee != expected_fee {
+        return Err(WalletError::Fee(tx.fee, expected_fee, unit.clone()).into());
+    }
+    Ok(())
+}
+
+/// Describes every input, output, the change and the fee of an unsigned transaction
+pub fn describe_tx(tx: &Tx, unit: &ZECUnit) -> Result<String> {
+    let mut s = String::new();
+    writeln!(s, "Transaction for height {}", tx.height)?;
+    writeln!(s, "Inputs:")?;
+    for input in tx.inputs.iter() {
+        writeln!(s, "  {} {} from {}", unit.from_satoshis(input.amount), unit, input.addr)?;
+    }
+    writeln!(s, "Outputs:")?;
+    for output in tx.outputs.iter() {
+        describe_output(&mut s, output, unit)?;
+    }
+    writeln!(s, "Change:")?;
+    match tx.change.as_ref() {
+        Some(change) => describe_output(&mut s, change, unit)?,
+        None => writeln!(s, "  None")?,
+    }
+    writeln!(s, "Fee: {} {}", unit.from_satoshis(tx.fee), unit)?;
+    Ok(s)
+}
+
+fn describe_output(s: &mut String, output: &TxOut, unit: &ZECUnit) -> Result<()> {
+    match output.addr_type {
+        AddressType::Shielded => {
+            writeln!(s, "  {} {} to {}", unit.from_satoshis(output.amount), unit, output.addr)?
+        }
+        AddressType::Transparent => writeln!(
+            s,
+            "  {} {} to {} UNSHIELDED: the amount and the recipient will be public",
+            unit.from_satoshis(output.amount),
+            unit,
+            output.addr
+        )?,
+    }
+    if let Some(memo) = output.memo.as_ref() {
+        match decode_memo(memo)? {
+            Memo::Text(text) => writeln!(s, "    memo: {}", &*text)?,
+            Memo::Empty => (),
+            _ => writeln!(s, "    memo (hex): {}", memo)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+const TTY: &str = "/dev/tty";
+#[cfg(windows)]
+const TTY: &str = "CONIN$";
+
+/// Asks the operator to type "y" to proceed.
+/// The answer comes from the terminal because stdin may carry the transaction itself
+pub fn confirm(prompt: &str) -> Result<()> {
+    eprint!("{} [y/N] ", prompt);
+    std::io::stderr().flush()?;
+    let tty = File::open(TTY).context("Cannot open the terminal to confirm the transaction")?;
+    let mut answer = String::new();
+    BufReader::new(tty).read_line(&mut answer)?;
+    match answer.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => Err(WalletError::Cancelled.into()),
+    }
+}
@@ -0,0 +1,306 @@
+use crate::keys::parse_phrase;
+use crate::{Result, WalletError};
+use bip39::{Language, Mnemonic};
+use blake2b_simd::Params;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+const SHARE_PREFIX: &str = "zcs1";
+const DIGEST_LEN: usize = 4;
+
+/// Splits the entropy of a seed phrase into `count` shares so that any `threshold`
+/// of them rebuild it (Shamir's secret sharing over GF(256), byte by byte).
+///
+/// A share reads `zcs1-<set id>-<threshold>-<index>-<hex data>-<checksum>`.
+/// The set id tells apart shares of different seeds and the checksum catches typos.
+/// A digest of the entropy is shared along with it, so combining shares
+/// of the wrong set is detected.
+pub fn split_phrase(phrase: &str, threshold: u8, count: u8) -> Result<Vec<String>> {
+    if threshold == 0 || threshold > count {
+        return Err(WalletError::Shares(format!(
+            "Threshold {} must be between 1 and the number of shares {}",
+            threshold, count
+        ))
+        .into());
+    }
+    let mnemonic = parse_phrase(phrase)?;
+    let mut secret = mnemonic.entropy().to_vec();
+    secret.extend_from_slice(&digest(mnemonic.entropy()));
+
+    let mut id = [0u8; 2];
+    OsRng.fill_bytes(&mut id);
+    let id = u16::from_be_bytes(id);
+
+    // coefficients[k] holds the k-th degree coefficients for every byte of the secret
+    let mut coefficients = vec![secret.clone()];
+    for _ in 1..threshold {
+        let mut c = vec![0u8; secret.len()];
+        OsRng.fill_bytes(&mut c);
+        coefficients.push(c);
+    }
+
+    let shares = (1..=count)
+        .map(|x| {
+            let data: Vec<u8> = (0..secret.len())
+                .map(|i| {
+                    // Horner's method
+                    coefficients
+                        .iter()
+                        .rev()
+                        .fold(0u8, |acc, c| gf_mul(acc, x) ^ c[i])
+                })
+                .collect();
+            encode_share(id, threshold, x, &data)
+        })
+        .collect();
+    Ok(shares)
+}
+
+/// Rebuilds the seed phrase from at least `threshold` shares
+pub fn combine_shares(shares: &[String]) -> Result<String> {
+    let shares = shares
+        .iter()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .enumerate()
+        .map(|(i, s)| decode_share(i + 1, s))
+        .collect::<Result<Vec<_>>>()?;
+    let first = shares
+        .first()
+        .ok_or_else(|| WalletError::Shares("No share".to_string()))?;
+    let (id, threshold, len) = (first.id, first.threshold, first.data.len());
+    let mut xs: Vec<u8> = Vec::new();
+    for share in shares.iter() {
+        if share.id != id || share.threshold != threshold || share.data.len() != len {
+            return Err(WalletError::Shares("Shares belong to different sets".to_string()).into());
+        }
+        if xs.contains(&share.index) {
+            return Err(WalletError::Shares(format!("Duplicate share {}", share.index)).into());
+        }
+        xs.push(share.index);
+    }
+    if shares.len() < threshold as usize {
+        return Err(WalletError::Shares(format!(
+            "Need {} shares, got {}",
+            threshold,
+            shares.len()
+        ))
+        .into());
+    }
+
+    // Lagrange interpolation at x = 0
+    let shares = &shares[..threshold as usize];
+    let mut secret = vec![0u8; len];
+    for (i, share) in shares.iter().enumerate() {
+        let mut basis = 1u8;
+        for (j, other) in shares.iter().enumerate() {
+            if i != j {
+                basis = gf_mul(basis, gf_div(other.index, other.index ^ share.index));
+            }
+        }
+        for (s, y) in secret.iter_mut().zip(share.data.iter()) {
+            *s ^= gf_mul(basis, *y);
+        }
+    }
+
+    if len <= DIGEST_LEN {
+        return Err(WalletError::Shares("Invalid share length".to_string()).into());
+    }
+    let (entropy, check) = secret.split_at(len - DIGEST_LEN);
+    if digest(entropy)[..] != check[..] {
+        return Err(WalletError::Shares("Shares do not combine into a valid seed".to_string()).into());
+    }
+    let mnemonic = Mnemonic::from_entropy(entropy, Language::English)?;
+    Ok(mnemonic.phrase().to_string())
+}
+
+struct Share {
+    id: u16,
+    threshold: u8,
+    index: u8,
+    data: Vec<u8>,
+}
+
+fn encode_share(id: u16, threshold: u8, index: u8, data: &[u8]) -> String {
+    let body = format!(
+        "{}-{:04x}-{}-{}-{}",
+        SHARE_PREFIX,
+        id,
+        threshold,
+        index,
+        hex::encode(data)
+    );
+    let checksum = hex::encode(digest(body.as_bytes()));
+    format!("{}-{}", body, checksum)
+}
+
+/// Errors give the position of the share in the list, not its content
+fn decode_share(position: usize, s: &str) -> Result<Share> {
+    let invalid = || WalletError::Shares(format!("Invalid share #{}", position));
+    let fields: Vec<&str> = s.split('-').collect();
+    if fields.len() != 6 || fields[0] != SHARE_PREFIX {
+        return Err(invalid().into());
+    }
+    let body = &s[..s.len() - fields[5].len() - 1];
+    if hex::encode(digest(body.as_bytes())) != fields[5] {
+        return Err(WalletError::Shares(format!("Checksum mismatch in share #{}", position)).into());
+    }
+    let share = Share {
+        id: u16::from_str_radix(fields[1], 16).map_err(|_| invalid())?,
+        threshold: fields[2].parse().map_err(|_| invalid())?,
+        index: fields[3].parse().map_err(|_| invalid())?,
+        data: hex::decode(fields[4]).map_err(|_| invalid())?,
+    };
+    if share.index == 0 || share.threshold == 0 {
+        return Err(invalid().into());
+    }
+    Ok(share)
+}
+
+fn digest(data: &[u8]) -> [u8; DIGEST_LEN] {
+    let hash = Params::new()
+        .hash_length(DIGEST_LEN)
+        .personal(b"ZColdWalletShare")
+        .hash(data);
+    let mut d = [0u8; DIGEST_LEN];
+    d.copy_from_slice(hash.as_bytes());
+    d
+}
+
+/// Multiplication in GF(2^8) with the AES polynomial x^8 + x^4 + x^3 + x + 1
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    p
+}
+
+fn gf_div(a: u8, b: u8) -> u8 {
+    // b^-1 = b^254 since b^255 = 1
+    let mut inv = 1u8;
+    for _ in 0..254 {
+        inv = gf_mul(inv, b);
+    }
+    gf_mul(a, inv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHRASE_12: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+    const PHRASE_24: &str = "abandon abandon abandon abandon abandon abandon abandon abandon \
+        abandon abandon abandon abandon abandon abandon abandon abandon \
+        abandon abandon abandon abandon abandon abandon abandon art";
+
+    fn subset(shares: &[String], mask: u32) -> Vec<String> {
+        shares
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, s)| s.clone())
+            .collect()
+    }
+
+    #[test]
+    fn gf_tables() {
+        // Examples of the AES specification (FIPS 197, 4.2)
+        assert_eq!(gf_mul(0x57, 0x83), 0xc1);
+        assert_eq!(gf_mul(0x57, 0x13), 0xfe);
+        for a in 0..=255u8 {
+            assert_eq!(gf_mul(a, 0), 0);
+            assert_eq!(gf_mul(a, 1), a);
+            if a != 0 {
+                assert_eq!(gf_mul(a, gf_div(1, a)), 1, "inverse of {}", a);
+                assert_eq!(gf_div(a, a), 1);
+            }
+            for b in 0..=255u8 {
+                assert_eq!(gf_mul(a, b), gf_mul(b, a));
+                if b != 0 {
+                    assert_eq!(gf_mul(gf_div(a, b), b), a);
+                }
+            }
+        }
+        // Every non zero element has a unique inverse
+        let mut inverses: Vec<u8> = (1..=255u8).map(|a| gf_div(1, a)).collect();
+        inverses.sort_unstable();
+        inverses.dedup();
+        assert_eq!(inverses.len(), 255);
+    }
+
+    #[test]
+    fn every_subset_of_threshold_shares_combines() {
+        for phrase in [PHRASE_12, PHRASE_24].iter() {
+            for count in 1..=5u8 {
+                for threshold in 1..=count {
+                    let shares = split_phrase(phrase, threshold, count).unwrap();
+                    assert_eq!(shares.len(), count as usize);
+                    for mask in 1..(1u32 << count) {
+                        let subset = subset(&shares, mask);
+                        let result = combine_shares(&subset);
+                        if subset.len() >= threshold as usize {
+                            assert_eq!(&result.unwrap(), phrase, "{} of {}, mask {:b}", threshold, count, mask);
+                        } else {
+                            assert!(result.is_err(), "{} of {}, mask {:b}", threshold, count, mask);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fewer_shares_do_not_reveal_the_secret() {
+        // Interpolating fewer points than the threshold gives another polynomial:
+        // claim a lower threshold and the digest no longer matches
+        let shares = split_phrase(PHRASE_12, 3, 5).unwrap();
+        let forged: Vec<String> = shares[..2]
+            .iter()
+            .map(|s| {
+                let share = decode_share(1, s).unwrap();
+                encode_share(share.id, 2, share.index, &share.data)
+            })
+            .collect();
+        if let Ok(phrase) = combine_shares(&forged) {
+            assert_ne!(phrase, PHRASE_12);
+        }
+    }
+
+    #[test]
+    fn checksum_rejects_typos() {
+        let shares = split_phrase(PHRASE_12, 2, 3).unwrap();
+        let mut share = shares[0].clone().into_bytes();
+        // A typo in the data part
+        let pos = share.len() - DIGEST_LEN * 2 - 3;
+        share[pos] = if share[pos] == b'0' { b'1' } else { b'0' };
+        let typo = String::from_utf8(share).unwrap();
+        let error = combine_shares(&[typo.clone(), shares[1].clone()]).unwrap_err();
+        assert!(error.to_string().contains("Checksum mismatch in share #1"));
+        // The share is secret material and stays out of the error
+        assert!(!error.to_string().contains(&typo[5..]));
+        assert!(combine_shares(&["zcs1-garbage".to_string()]).is_err());
+    }
+
+    #[test]
+    fn mixed_or_duplicate_shares_fail() {
+        let a = split_phrase(PHRASE_12, 2, 3).unwrap();
+        let b = split_phrase(PHRASE_12, 2, 3).unwrap();
+        assert!(combine_shares(&[a[0].clone(), a[0].clone()]).is_err());
+        // Unless the random set ids collide
+        if a[0][5..9] != b[0][5..9] {
+            assert!(combine_shares(&[a[0].clone(), b[1].clone()]).is_err());
+        }
+        assert!(split_phrase(PHRASE_12, 3, 2).is_err());
+        assert!(split_phrase(PHRASE_12, 0, 2).is_err());
+    }
+}
@@ -1,29 +1,44 @@
-use crate::constants::{HRP_SAPLING_EXTENDED_SPENDING_KEY, HRP_SAPLING_PAYMENT_ADDRESS, NETWORK};
-use crate::{grpc::RawTransaction, Opt, Result, Tx, WalletError};
+use crate::review::check_fee;
+use crate::transact::MAX_MEMO_SIZE;
+use crate::{grpc::RawTransaction, network::Params, AddressType, Opt, Result, Tx, WalletError};
 use jubjub::Fr;
-use zcash_client_backend::encoding::{decode_extended_spending_key, decode_payment_address};
+use std::convert::TryFrom;
+use zcash_client_backend::encoding::{
+    decode_extended_spending_key, decode_payment_address, decode_transparent_address,
+};
 use zcash_primitives::{
-    consensus::{BlockHeight, BranchId},
+    consensus::{BlockHeight, BranchId, Parameters},
+    memo::{Memo, MemoBytes},
     merkle_tree::IncrementalWitness,
     primitives::{Diversifier, Rseed},
     sapling::Node,
     transaction::{builder::Builder, components::Amount},
+    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
 };
 use zcash_proofs::prover::LocalTxProver;
 
-pub fn sign_tx(spending_key: &str, tx: &Tx, opts: &Opt) -> Result<RawTransaction> {
-    let extsk = decode_extended_spending_key(HRP_SAPLING_EXTENDED_SPENDING_KEY, &spending_key)?
-        .ok_or_else(|| WalletError::Decode(spending_key.to_string()))?;
+/// Decodes a spending key. Errors do not include the key
+pub fn decode_spending_key(network: &Params, spending_key: &str) -> Result<ExtendedSpendingKey> {
+    let extsk = decode_extended_spending_key(network.hrp_sapling_extended_spending_key(), &spending_key)
+        .ok()
+        .flatten()
+        .ok_or(WalletError::InvalidSpendingKey)?;
+    Ok(extsk)
+}
+
+pub fn sign_tx(extsk: &ExtendedSpendingKey, tx: &Tx, opts: &Opt) -> Result<RawTransaction> {
+    check_fee(tx, opts)?;
     let ovk = extsk.expsk.ovk;
     let prover = LocalTxProver::with_default_location().ok_or(WalletError::Prover)?;
     let height = BlockHeight::from_u32(tx.height as u32);
-    let consensus_branch_id = BranchId::for_height(&NETWORK, height);
-    let mut builder = Builder::new(NETWORK, height);
+    let network = &opts.network;
+    let consensus_branch_id = BranchId::for_height(network, height);
+    let mut builder = Builder::new(network.clone(), height);
     for input in tx.inputs.iter() {
         let mut d = [0u8; 11];
         hex::decode_to_slice(&input.diversifier, &mut d)?;
         let diversifier = Diversifier(d);
-        let from = decode_payment_address(HRP_SAPLING_PAYMENT_ADDRESS, &input.addr)?.unwrap();
+        let from = decode_payment_address(network.hrp_sapling_payment_address(), &input.addr)?.unwrap();
         let mut rseed = [0u8; 32];
         hex::decode_to_slice(&input.rseed, &mut rseed)?;
         let rseed = if input.z212 {
@@ -37,20 +52,48 @@ pub fn sign_tx(spending_key: &str, tx: &Tx, opts: &Opt) -> Result<RawTransaction
         let merkle_path = witness.path().unwrap();
         builder.add_sapling_spend(extsk.clone(), diversifier, note, merkle_path)?;
     }
-    let output = tx.output.as_ref().unwrap();
-    let output_addr = decode_payment_address(HRP_SAPLING_PAYMENT_ADDRESS, &output.addr)?.unwrap();
-    eprintln!(
-        "Payment of {} {} to {}",
-        opts.unit.from_satoshis(output.amount),
-        opts.unit,
-        output.addr
-    );
-    builder.add_sapling_output(
-        Some(ovk),
-        output_addr,
-        Amount::from_u64(output.amount).unwrap(),
-        None,
-    )?;
+    for output in tx.outputs.iter() {
+        let amount = Amount::from_u64(output.amount).unwrap();
+        match output.addr_type {
+            AddressType::Shielded => {
+                let output_addr =
+                    decode_payment_address(network.hrp_sapling_payment_address(), &output.addr)?
+                        .ok_or_else(|| WalletError::Decode(output.addr.clone()))?;
+                let memo = match output.memo.as_ref() {
+                    Some(memo) => Some(MemoBytes::from(decode_memo(memo)?)),
+                    None => None,
+                };
+                builder.add_sapling_output(Some(ovk), output_addr, amount, memo)?;
+            }
+            AddressType::Transparent => {
+                let output_addr = decode_transparent_address(
+                    &network.b58_pubkey_address_prefix(),
+                    &network.b58_script_address_prefix(),
+                    &output.addr,
+                )?
+                .ok_or_else(|| WalletError::Decode(output.addr.clone()))?;
+                if output.memo.is_some() {
+                    return Err(WalletError::TransparentMemo(output.addr.clone()).into());
+                }
+                builder.add_transparent_output(&output_addr, amount)?;
+            }
+        }
+    }
+    if let Some(change) = tx.change.as_ref() {
+        let change_addr = decode_payment_address(network.hrp_sapling_payment_address(), &change.addr)?
+            .ok_or_else(|| WalletError::Decode(change.addr.clone()))?;
+        // The change must go back to an address of the key that signs
+        let extfvk = ExtendedFullViewingKey::from(extsk);
+        if extfvk.fvk.vk.to_payment_address(*change_addr.diversifier()) != Some(change_addr.clone()) {
+            return Err(WalletError::ChangeAddress(change.addr.clone()).into());
+        }
+        builder.add_sapling_output(
+            Some(ovk),
+            change_addr,
+            Amount::from_u64(change.amount).unwrap(),
+            None,
+        )?;
+    }
     let (tx, _) = builder.build(consensus_branch_id, &prover)?;
     let mut raw_tx = vec![];
     tx.write(&mut raw_tx)?;
@@ -62,3 +105,13 @@ pub fn sign_tx(spending_key: &str, tx: &Tx, opts: &Opt) -> Result<RawTransaction
 
     Ok(raw_tx)
 }
+
+pub(crate) fn decode_memo(memo: &str) -> Result<Memo> {
+    let memo = hex::decode(memo)?;
+    if memo.len() > MAX_MEMO_SIZE {
+        return Err(WalletError::MemoTooLong(memo.len()).into());
+    }
+    let memo = MemoBytes::from_bytes(&memo).map_err(|_| WalletError::InvalidMemo)?;
+    let memo = Memo::try_from(memo).map_err(|_| WalletError::InvalidMemo)?;
+    Ok(memo)
+}
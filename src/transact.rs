@@ -1,28 +1,90 @@
-use crate::constants::{HRP_SAPLING_PAYMENT_ADDRESS, NETWORK};
-use crate::{grpc::RawTransaction, Result, Tx, TxIn, TxOut, ACCOUNT, DATA_PATH, WalletError, connect_lightnode, ZECUnit};
+use crate::review::check_supported_fee;
+use crate::sign::decode_memo;
+use crate::{grpc::RawTransaction, AddressType, Opt, Result, Tx, TxIn, TxOut, WalletError, ZECUnit, connect_lightnode};
 use zcash_client_backend::{
-    address::RecipientAddress, data_api::WalletRead, encoding::encode_payment_address,
+    address::RecipientAddress,
+    data_api::WalletRead,
+    encoding::{encode_payment_address, encode_transparent_address},
 };
-use zcash_client_sqlite::WalletDB;
 use zcash_primitives::{
+    consensus::Parameters,
     primitives::Rseed,
-    transaction::components::{amount::DEFAULT_FEE, Amount},
+    transaction::components::Amount,
 };
 
-pub fn prepare_tx(to_addr: &str, amount: String, unit: &ZECUnit) -> Result<Tx> {
-    let satoshis = unit.to_satoshis(&amount);
-    let to_addr = RecipientAddress::decode(&NETWORK, to_addr).ok_or_else(|| WalletError::Decode(to_addr.to_string()))?;
-    let amount = Amount::from_u64(satoshis).expect("Invalid amount");
-    let wallet_db = WalletDB::for_path(DATA_PATH, NETWORK)?;
+pub const MAX_MEMO_SIZE: usize = 512;
+
+pub struct Payment {
+    pub addr: String,
+    /// In zatoshis
+    pub amount: u64,
+    pub memo: Option<Vec<u8>>,
+}
+
+/// Parses a list of payments, one `address,amount[,memo]` per line, amounts in `unit`.
+/// The memo is text and may contain commas.
+/// Blank lines and lines starting with `#` are ignored
+pub fn read_payments_csv(csv: &str, unit: &ZECUnit) -> Result<Vec<Payment>> {
+    let mut payments = Vec::new();
+    for (i, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || WalletError::InvalidRecipient(i + 1, line.to_string());
+        let mut fields = line.splitn(3, ',').map(str::trim);
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(addr), Some(amount), memo) if !addr.is_empty() && !amount.is_empty() => {
+                payments.push(Payment {
+                    addr: addr.to_string(),
+                    amount: unit.to_satoshis(amount).map_err(|_| invalid())?,
+                    memo: memo
+                        .filter(|memo| !memo.is_empty())
+                        .map(|memo| memo.as_bytes().to_vec()),
+                })
+            }
+            _ => return Err(invalid().into()),
+        }
+    }
+    Ok(payments)
+}
+
+pub fn prepare_tx(payments: &[Payment], opts: &Opt) -> Result<Tx> {
+    let unit = &opts.unit;
+    if payments.is_empty() {
+        return Err(WalletError::NoRecipient.into());
+    }
+    let mut recipients = Vec::new();
+    for payment in payments.iter() {
+        let to_addr = RecipientAddress::decode(&opts.network, &payment.addr)
+            .ok_or_else(|| WalletError::Decode(payment.addr.clone()))?;
+        let amount = Amount::from_u64(payment.amount)
+            .map_err(|_| WalletError::InvalidAmount(unit.from_satoshis(payment.amount)))?;
+        let memo = payment.memo.as_ref().map(hex::encode);
+        if let Some(memo) = memo.as_ref() {
+            if let RecipientAddress::Transparent(_) = to_addr {
+                return Err(WalletError::TransparentMemo(payment.addr.clone()).into());
+            }
+            // Same check as the signer, so that a bad memo fails here and not on the offline computer
+            decode_memo(memo)?;
+        }
+        recipients.push((to_addr, amount, memo));
+    }
+    let total: Amount = recipients.iter().map(|(_, amount, _)| *amount).sum();
+    let wallet_db = opts.wallet_db()?;
     let fvks = wallet_db.get_extended_full_viewing_keys()?;
-    let extfvk = &fvks[&ACCOUNT];
+    let extfvk = fvks
+        .get(&opts.account)
+        .ok_or(WalletError::AccountNotFound(opts.account.0))?;
     let ovk = extfvk.fvk.ovk;
 
     // Target the next block, assuming we are up-to-date.
     let (height, anchor_height) = wallet_db.get_target_and_anchor_heights()?.unwrap();
 
-    let target_value = amount + DEFAULT_FEE;
-    let spendable_notes = wallet_db.select_spendable_notes(ACCOUNT, target_value, anchor_height)?;
+    check_supported_fee(opts.fee)?;
+    let fee = Amount::from_u64(opts.fee).or(Err(WalletError::InvalidFee(opts.fee)))?;
+    let target_value = total + fee;
+    let spendable_notes = wallet_db.select_spendable_notes(opts.account, target_value, anchor_height)?;
 
     // Confirm we were able to select sufficient value
     let selected_value: Amount = spendable_notes.iter().map(|n| n.note_value).sum();
@@ -37,7 +99,9 @@ pub fn prepare_tx(to_addr: &str, amount: String, unit: &ZECUnit) -> Result<Tx> {
     let mut tx = Tx {
         height: i64::from(height),
         inputs: Vec::new(),
-        output: None,
+        outputs: Vec::new(),
+        change: None,
+        fee: opts.fee,
     };
 
     // Create the transaction
@@ -49,7 +113,7 @@ pub fn prepare_tx(to_addr: &str, amount: String, unit: &ZECUnit) -> Result<Tx> {
             .expect("Could not convert viewing key to payment address");
 
         let d = selected.diversifier.0;
-        let paddr = encode_payment_address(HRP_SAPLING_PAYMENT_ADDRESS, &from);
+        let paddr = encode_payment_address(opts.network.hrp_sapling_payment_address(), &from);
         let a = u64::from(selected.note_value);
         let (rseed, z212) = match selected.rseed {
             Rseed::BeforeZip212(s) => (s.to_bytes(), false),
@@ -68,29 +132,120 @@ pub fn prepare_tx(to_addr: &str, amount: String, unit: &ZECUnit) -> Result<Tx> {
         });
     }
 
-    match to_addr {
-        RecipientAddress::Shielded(to) => {
-            tx.output = Some(TxOut {
-                ovk: hex::encode(ovk.0),
-                addr: encode_payment_address(HRP_SAPLING_PAYMENT_ADDRESS, &to),
-                amount: u64::from(amount),
-            });
+    for (to_addr, amount, memo) in recipients {
+        match to_addr {
+            RecipientAddress::Shielded(to) => {
+                tx.outputs.push(TxOut {
+                    addr_type: AddressType::Shielded,
+                    ovk: hex::encode(ovk.0),
+                    addr: encode_payment_address(opts.network.hrp_sapling_payment_address(), &to),
+                    amount: u64::from(amount),
+                    memo,
+                });
+            }
+
+            RecipientAddress::Transparent(to) => {
+                tx.outputs.push(TxOut {
+                    addr_type: AddressType::Transparent,
+                    ovk: hex::encode(ovk.0),
+                    addr: encode_transparent_address(
+                        &opts.network.b58_pubkey_address_prefix(),
+                        &opts.network.b58_script_address_prefix(),
+                        &to,
+                    ),
+                    amount: u64::from(amount),
+                    memo: None,
+                });
+            }
         }
+    }
 
-        RecipientAddress::Transparent(_) => unimplemented!(),
+    // Send the change back to the default address of the account
+    let change = selected_value - target_value;
+    if change.is_positive() {
+        let (_, change_addr) = extfvk.default_address().unwrap();
+        tx.change = Some(TxOut {
+            addr_type: AddressType::Shielded,
+            ovk: hex::encode(ovk.0),
+            addr: encode_payment_address(opts.network.hrp_sapling_payment_address(), &change_addr),
+            amount: u64::from(change),
+            memo: None,
+        });
     }
 
     Ok(tx)
 }
 
-pub async fn submit(raw_tx: RawTransaction, lightnode_url: &str) -> Result<()> {
-    let mut client = connect_lightnode(lightnode_url.to_string()).await?;
+/// Broadcasts the transaction and returns its id
+pub async fn submit(raw_tx: RawTransaction, opts: &Opt) -> Result<String> {
+    let mut client = connect_lightnode(opts).await?;
     let r = client.send_transaction(raw_tx).await?.into_inner();
 
     if r.error_code != 0 {
         return Err(WalletError::Submit(r.error_code, r.error_message).into())
     }
-    println!("Success! tx id: {}", r.error_message);
+    // On success, lightwalletd puts the quoted tx id in the error message
+    let txid = r.error_message.trim_matches('"').to_string();
+    Ok(txid)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_line(csv: &str) -> usize {
+        match read_payments_csv(csv, &ZECUnit::Zec) {
+            Err(error) => match error.downcast::<WalletError>() {
+                Ok(WalletError::InvalidRecipient(line, _)) => line,
+                _ => panic!("expected an invalid recipient"),
+            },
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn payments_with_memos_comments_and_blank_lines() {
+        let csv = "# address,amount,memo\n\
+                   \n\
+                   zs1first, 1.5\n\
+                   \x20\x20# indented comment\n\
+                   zs1second,0.00000001,Thanks, see you soon\n\
+                   zs1third,2,\n";
+        let payments = read_payments_csv(csv, &ZECUnit::Zec).unwrap();
+        assert_eq!(payments.len(), 3);
+        assert_eq!(payments[0].addr, "zs1first");
+        assert_eq!(payments[0].amount, 150_000_000);
+        assert!(payments[0].memo.is_none());
+        assert_eq!(payments[1].amount, 1);
+        assert_eq!(payments[1].memo.as_deref(), Some(&b"Thanks, see you soon"[..]));
+        assert_eq!(payments[2].amount, 200_000_000);
+        assert!(payments[2].memo.is_none());
+    }
+
+    #[test]
+    fn amounts_are_in_the_unit() {
+        let payments = read_payments_csv("zs1a,2\nzs1b,1.5", &ZECUnit::MilliZec).unwrap();
+        assert_eq!(payments[0].amount, 200_000);
+        assert_eq!(payments[1].amount, 150_000);
+        let payments = read_payments_csv("zs1a,1000", &ZECUnit::Zat).unwrap();
+        assert_eq!(payments[0].amount, 1_000);
+    }
+
+    #[test]
+    fn bad_amounts_give_the_line() {
+        assert_eq!(invalid_line("zs1a,1\nzs1b,0.4 ZEC"), 2);
+        assert_eq!(invalid_line("# header\nzs1a,-1"), 2);
+        assert_eq!(invalid_line("zs1a,abc"), 1);
+        // Less than a zatoshi
+        assert_eq!(invalid_line("zs1a,0.000000001"), 1);
+        // More than the supply
+        assert_eq!(invalid_line("zs1a,21000001"), 1);
+    }
+
+    #[test]
+    fn missing_fields_give_the_line() {
+        assert_eq!(invalid_line("zs1a"), 1);
+        assert_eq!(invalid_line("zs1a,1\n\nzs1b,"), 3);
+        assert_eq!(invalid_line(",1"), 1);
+    }
 }
@@ -0,0 +1,240 @@
+//! Prepares and signs a transaction on a regtest wallet, without lightwalletd.
+//! The blocks are made up and written to the block cache directly.
+//! Signing needs the Sapling parameters, so that test only runs with `--ignored`
+
+use prost::Message;
+use rand::RngCore;
+use rusqlite::{params, Connection};
+use std::path::PathBuf;
+use zcash_client_backend::wallet::AccountId;
+use zcash_client_sqlite::wallet::init::{init_accounts_table, init_blocks_table};
+use zcash_coldwallet::{
+    chain::{init_db, scan},
+    config::TlsConfig,
+    grpc::{CompactBlock, CompactOutput, CompactTx},
+    keys::{restore_key, spending_key_from_secret},
+    network::{Params, RegtestParams},
+    pszt::{extract_pszt, finalize_pszt, sign_pszt, Pszt},
+    review::check_fee,
+    sign::sign_tx,
+    transact::{prepare_tx, Payment},
+    Opt, Tx, ZECUnit, CACHE_PATH, DATA_PATH,
+};
+use zcash_primitives::{
+    block::BlockHash,
+    consensus::BlockHeight,
+    memo::MemoBytes,
+    merkle_tree::CommitmentTree,
+    note_encryption::SaplingNoteEncryption,
+    primitives::Rseed,
+    sapling::Node,
+    transaction::{components::amount::DEFAULT_FEE, Transaction},
+    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+};
+
+const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+const BIRTH_HEIGHT: u32 = 1;
+const NOTE_VALUE: u64 = 1_000_000;
+/// Enough blocks on top of the note for it to be at the anchor height
+const BLOCK_COUNT: u32 = 12;
+
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn random_bytes() -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Block that pays `value` to the default address of `extfvk`
+fn block_with_note(
+    height: u32,
+    prev_hash: &[u8],
+    extfvk: &ExtendedFullViewingKey,
+    value: u64,
+) -> CompactBlock {
+    let (_, to) = extfvk.default_address().unwrap();
+    let note = to
+        .create_note(value, Rseed::AfterZip212(random_bytes()))
+        .unwrap();
+    let encryptor = SaplingNoteEncryption::new(
+        Some(extfvk.fvk.ovk),
+        note.clone(),
+        to,
+        MemoBytes::empty(),
+        rand_core::OsRng,
+    );
+    let epk = jubjub::AffinePoint::from(jubjub::ExtendedPoint::from(*encryptor.epk()));
+    let output = CompactOutput {
+        cmu: note.cmu().to_bytes().to_vec(),
+        epk: epk.to_bytes().to_vec(),
+        ciphertext: encryptor.encrypt_note_plaintext()[..52].to_vec(),
+    };
+    let tx = CompactTx {
+        hash: random_bytes().to_vec(),
+        outputs: vec![output],
+        ..Default::default()
+    };
+    CompactBlock {
+        vtx: vec![tx],
+        ..empty_block(height, prev_hash)
+    }
+}
+
+fn empty_block(height: u32, prev_hash: &[u8]) -> CompactBlock {
+    CompactBlock {
+        height: u64::from(height),
+        hash: random_bytes().to_vec(),
+        prev_hash: prev_hash.to_vec(),
+        time: 1_600_000_000 + height * 75,
+        ..Default::default()
+    }
+}
+
+struct Wallet {
+    network: Params,
+    opts: Opt,
+    extsk: ExtendedSpendingKey,
+    _data_dir: TempDir,
+}
+
+/// Regtest wallet that received a note of `NOTE_VALUE` at height 2 and scanned up to height 13
+fn funded_wallet(name: &str) -> Wallet {
+    let network = Params::regtest(RegtestParams::default());
+    let data_dir = TempDir(std::env::temp_dir().join(format!(
+        "zcash-coldwallet-regtest-{}-{}",
+        name,
+        std::process::id()
+    )));
+    let opts = Opt {
+        lightnode_url: network.network_type.default_lightnode_url().to_string(),
+        unit: ZECUnit::Zec,
+        account: AccountId(0),
+        data_dir: data_dir.0.clone(),
+        data_file: PathBuf::from(DATA_PATH),
+        cache_file: PathBuf::from(CACHE_PATH),
+        network: network.clone(),
+        fee: u64::from(DEFAULT_FEE),
+        tls: TlsConfig::default(),
+    };
+
+    let extsk = spending_key_from_secret(&network, PHRASE, "", 0).unwrap();
+    let extfvk = ExtendedFullViewingKey::from(&extsk);
+    init_db(&opts).unwrap();
+    let db_data = opts.wallet_db().unwrap();
+    init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+    let mut tree_bytes = Vec::new();
+    CommitmentTree::<Node>::empty().write(&mut tree_bytes).unwrap();
+    let birth_hash = random_bytes();
+    init_blocks_table(
+        &db_data,
+        BlockHeight::from_u32(BIRTH_HEIGHT),
+        BlockHash(birth_hash),
+        1_600_000_000,
+        &tree_bytes,
+    )
+    .unwrap();
+
+    let cache = Connection::open(opts.cache_path()).unwrap();
+    let mut prev_hash = birth_hash.to_vec();
+    for height in BIRTH_HEIGHT + 1..=BIRTH_HEIGHT + BLOCK_COUNT {
+        let block = if height == BIRTH_HEIGHT + 1 {
+            block_with_note(height, &prev_hash, &extfvk, NOTE_VALUE)
+        } else {
+            empty_block(height, &prev_hash)
+        };
+        let mut data = Vec::new();
+        block.encode(&mut data).unwrap();
+        cache
+            .execute(
+                "INSERT INTO compactblocks (height, data) VALUES (?, ?)",
+                params![height, data],
+            )
+            .unwrap();
+        prev_hash = block.hash;
+    }
+    scan(&opts).unwrap();
+
+    Wallet {
+        network,
+        opts,
+        extsk,
+        _data_dir: data_dir,
+    }
+}
+
+fn prepare_payment(wallet: &Wallet) -> Tx {
+    let recipient = restore_key(&wallet.network, PHRASE, "", 1).unwrap();
+    let payments = [Payment {
+        addr: recipient.address,
+        amount: 100_000,
+        memo: Some(b"regtest".to_vec()),
+    }];
+    let tx = prepare_tx(&payments, &wallet.opts).unwrap();
+    assert_eq!(tx.inputs.len(), 1);
+    assert_eq!(tx.outputs.len(), 1);
+    assert_eq!(tx.outputs[0].amount, 100_000);
+    assert_eq!(
+        tx.change.as_ref().unwrap().amount,
+        NOTE_VALUE - 100_000 - tx.fee
+    );
+    tx
+}
+
+#[test]
+fn prepare_on_regtest() {
+    let wallet = funded_wallet("prepare");
+    let tx = prepare_payment(&wallet);
+    check_fee(&tx, &wallet.opts).unwrap();
+
+    // The PSZT of a real transaction, with a witness, goes through serialization and the MAC
+    let ovk = ExtendedFullViewingKey::from(&wallet.extsk).fvk.ovk;
+    let network_type = wallet.network.network_type;
+    let pszt = Pszt::new(tx, &ovk, network_type).unwrap();
+    assert_ne!(pszt.anchor, [0u8; 32]);
+    let mut data = Vec::new();
+    pszt.write(&mut data).unwrap();
+    let read = Pszt::read(&data[..], Some(&ovk), network_type).unwrap();
+    assert_eq!(read.anchor, pszt.anchor);
+    assert_eq!(
+        serde_json::to_string(&read.tx).unwrap(),
+        serde_json::to_string(&pszt.tx).unwrap()
+    );
+    let other_ovk = ExtendedFullViewingKey::from(
+        &spending_key_from_secret(&wallet.network, PHRASE, "", 1).unwrap(),
+    )
+    .fvk
+    .ovk;
+    assert!(Pszt::read(&data[..], Some(&other_ovk), network_type).is_err());
+}
+
+/// Needs the Sapling parameters in their default location (zcash-fetch-params):
+/// `cargo test -- --ignored`
+#[test]
+#[ignore]
+fn sign_on_regtest() {
+    let wallet = funded_wallet("sign");
+    let tx = prepare_payment(&wallet);
+    let opts = &wallet.opts;
+
+    let raw_tx = sign_tx(&wallet.extsk, &tx, opts).unwrap();
+    let signed_tx = Transaction::read(&raw_tx.data[..]).unwrap();
+    assert_eq!(signed_tx.shielded_spends.len(), 1);
+    assert!(signed_tx.vout.is_empty());
+
+    let ovk = ExtendedFullViewingKey::from(&wallet.extsk).fvk.ovk;
+    let network_type = wallet.network.network_type;
+    let mut pszt = Pszt::new(tx, &ovk, network_type).unwrap();
+    sign_pszt(&wallet.extsk, &mut pszt, opts).unwrap();
+    let mut data = Vec::new();
+    pszt.write(&mut data).unwrap();
+    let mut pszt = Pszt::read(&data[..], Some(&ovk), network_type).unwrap();
+    finalize_pszt(&mut pszt, &wallet.network).unwrap();
+    assert!(!extract_pszt(&pszt).unwrap().data.is_empty());
+}